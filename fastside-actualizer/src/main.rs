@@ -357,6 +357,7 @@ async fn main() -> Result<()> {
                 .into_iter()
                 .map(|service| (service.name.clone(), service))
                 .collect();
+            let groups = stored_data.groups;
 
             // Check if all services from update_service_names exist
             if let Some(update_service_names) = update_service_names {
@@ -460,6 +461,7 @@ async fn main() -> Result<()> {
                     }
                     stored_services
                 },
+                groups,
             };
             // Write services back to file
             let services_content = serde_json::to_string_pretty(&stored_data)
@@ -1,5 +1,8 @@
 use async_trait::async_trait;
-use fastside_shared::serde_types::{HttpCodeRanges, Instance, Service};
+use fastside_shared::{
+    serde_types::{HttpCodeRanges, Instance, Service},
+    url_utils::join_instance_url,
+};
 use reqwest::Client;
 
 use crate::types::InstanceChecker;
@@ -29,7 +32,7 @@ impl InstanceChecker for DefaultInstanceChecker {
         service: &Service,
         instance: &Instance,
     ) -> anyhow::Result<bool> {
-        let url = instance.url.join(&service.test_url)?;
+        let url = join_instance_url(&instance.url, &service.test_url)?;
         let response = client.get(url).send().await?;
         let status_code = response.status().as_u16();
         if service.allowed_http_codes.is_allowed(status_code) {
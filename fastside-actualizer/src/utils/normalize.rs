@@ -2,9 +2,20 @@ use std::collections::HashSet;
 
 use fastside_shared::serde_types::Instance;
 
-/// Normalize instances by removing duplicates and sorting them.
+/// Normalize instances by adding trailing slash to sub-path URLs, removing
+/// duplicates and sorting them.
 pub fn normalize_instances(instances: &[Instance]) -> Vec<Instance> {
-    let set: HashSet<Instance> = instances.iter().cloned().collect();
+    let set: HashSet<Instance> = instances
+        .iter()
+        .cloned()
+        .map(|mut instance| {
+            if !instance.url.path().ends_with('/') {
+                let path = format!("{}/", instance.url.path());
+                instance.url.set_path(&path);
+            }
+            instance
+        })
+        .collect();
     let mut vec: Vec<Instance> = set.into_iter().collect();
     vec.sort();
     vec
@@ -1,4 +1,4 @@
-use reqwest::Client;
+use reqwest::{Client, ClientBuilder};
 
 use crate::{
     config::{CrawlerConfig, ProxyData},
@@ -25,12 +25,13 @@ fn default_headers() -> reqwest::header::HeaderMap {
     headers
 }
 
-pub fn build_client(
+/// Client builder for checking instance, configured from service and crawler config.
+pub fn client_builder(
     service: &Service,
     config: &CrawlerConfig,
     proxies: &ProxyData,
     instance: &Instance,
-) -> Result<Client, reqwest::Error> {
+) -> Result<ClientBuilder, reqwest::Error> {
     let redirect_policy = if service.follow_redirects {
         reqwest::redirect::Policy::default()
     } else {
@@ -70,5 +71,14 @@ pub fn build_client(
         client_builder = client_builder.proxy(proxy);
     }
 
-    client_builder.build()
+    Ok(client_builder)
+}
+
+pub fn build_client(
+    service: &Service,
+    config: &CrawlerConfig,
+    proxies: &ProxyData,
+    instance: &Instance,
+) -> Result<Client, reqwest::Error> {
+    client_builder(service, config, proxies, instance)?.build()
 }
@@ -1,12 +1,13 @@
 //! Application configuration.
 
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{borrow::Cow, collections::HashMap, path::PathBuf, time::Duration};
 
 use anyhow::{Context, Result};
 use config::Config;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
-use crate::errors::UserConfigError;
+use crate::{config_string, errors::UserConfigError, serde_types::Service, tag_filter::TagFilter};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DomainRequestTimeout {
@@ -27,6 +28,58 @@ const fn default_max_concurrent_requests() -> usize {
     200
 }
 
+const fn default_history_size() -> usize {
+    // Last 24 hours with default ping interval
+    288
+}
+
+const fn default_custom_cache_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_max_custom_instances() -> usize {
+    8
+}
+
+const fn default_max_concurrent_custom_checks() -> usize {
+    16
+}
+
+/// Health checks of instances supplied by users in [`UserConfig::custom_instances`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CustomInstancesConfig {
+    /// Off by default: configs with custom instances can be shared by links,
+    /// so enabling lets a single click send redirects to any host.
+    #[serde(default)]
+    pub enabled: bool,
+    /// How long check results are reused.
+    #[serde(default = "default_custom_cache_ttl")]
+    pub cache_ttl: Duration,
+    /// Maximum number of custom instances checked per request.
+    #[serde(default = "default_max_custom_instances")]
+    pub max_instances: usize,
+    /// Maximum number of checks running at once across all requests. Custom
+    /// instances are skipped while the limit is reached.
+    #[serde(default = "default_max_concurrent_custom_checks")]
+    pub max_concurrent_checks: usize,
+    /// Allow instances resolving to loopback, private and other non-public
+    /// addresses. Enable only if users are trusted.
+    #[serde(default)]
+    pub allow_private: bool,
+}
+
+impl Default for CustomInstancesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cache_ttl: default_custom_cache_ttl(),
+            max_instances: default_max_custom_instances(),
+            max_concurrent_checks: default_max_concurrent_custom_checks(),
+            allow_private: false,
+        }
+    }
+}
+
 /// Crawler configuration.
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct CrawlerConfig {
@@ -38,6 +91,11 @@ pub struct CrawlerConfig {
     pub domain_request_timeouts: Vec<DomainRequestTimeout>,
     #[serde(default = "default_max_concurrent_requests")]
     pub max_concurrent_requests: usize,
+    /// Number of last crawl results kept per instance to compute uptime.
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    #[serde(default)]
+    pub custom_instances: CustomInstancesConfig,
 }
 
 impl CrawlerConfig {
@@ -70,6 +128,40 @@ pub enum SelectMethod {
     #[default]
     Random,
     LowPing,
+    /// Random instance with probability inversely proportional to its latency
+    /// raised to [`UserConfig::latency_exponent`].
+    WeightedLatency,
+    /// Same instance for the same [`UserConfig::sticky_seed`] while it is alive.
+    Sticky,
+    /// Instance with the highest uptime, lowest latency on ties.
+    MostReliable,
+    /// Instance closest to client by GeoIP, lowest latency on ties.
+    Nearest,
+    /// Instance with the fewest recent redirects relative to its weight,
+    /// lowest latency on ties.
+    LeastLoaded,
+}
+
+const fn default_latency_exponent() -> f64 {
+    1.0
+}
+
+/// Largest accepted [`UserConfig::latency_exponent`]. Higher values only
+/// overflow weights of slow instances to zero.
+const MAX_LATENCY_EXPONENT: f64 = 8.0;
+
+/// Parse latency exponent, clamping it to `0..=MAX_LATENCY_EXPONENT`.
+fn deserialize_latency_exponent<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let exponent = f64::deserialize(deserializer)?;
+    if exponent.is_nan() {
+        return Err(serde::de::Error::custom(
+            "latency_exponent must be a number",
+        ));
+    }
+    Ok(exponent.clamp(0.0, MAX_LATENCY_EXPONENT))
 }
 
 fn default_required_tags() -> Vec<String> {
@@ -80,35 +172,172 @@ fn default_required_tags() -> Vec<String> {
     ]
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+/// Per-service overrides of [`UserConfig`]. Set fields replace global ones.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ServiceOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forbidden_tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_filter: Option<TagFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select_method: Option<SelectMethod>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_instances: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore_fallback_warning: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct UserConfig {
     #[serde(default = "default_required_tags")]
     pub required_tags: Vec<String>,
     #[serde(default)]
     pub forbidden_tags: Vec<String>,
+    /// Boolean expression over instance tags, checked in addition to
+    /// required and forbidden tags.
+    #[serde(default)]
+    pub tag_filter: Option<TagFilter>,
     #[serde(default)]
     pub select_method: SelectMethod,
+    /// Exponent of latency weights used by [`SelectMethod::WeightedLatency`].
+    /// `0` is uniform, higher values prefer fast instances more.
+    #[serde(
+        default = "default_latency_exponent",
+        deserialize_with = "deserialize_latency_exponent"
+    )]
+    pub latency_exponent: f64,
+    /// Minimum uptime of instance in percent. Instances below are never selected.
+    #[serde(default)]
+    pub min_uptime: f64,
+    /// Per-user random seed used by [`SelectMethod::Sticky`].
+    #[serde(default)]
+    pub sticky_seed: Option<String>,
     #[serde(default)]
     pub ignore_fallback_warning: bool,
+    /// Do not show notice when redirecting from deprecated service to its replacement.
+    #[serde(default)]
+    pub ignore_replacement_notice: bool,
     #[serde(default)]
     pub preferred_instances: Vec<String>,
+    /// User-supplied instance URLs per service name or alias. They are
+    /// checked on demand and considered alongside crawled instances.
+    #[serde(default)]
+    pub custom_instances: HashMap<String, Vec<Url>>,
+    /// Hosts of instances that are never selected.
+    #[serde(default)]
+    pub blocked_instances: Vec<String>,
+    /// Preferred order of services per service group.
+    #[serde(default)]
+    pub group_preferences: HashMap<String, Vec<String>>,
+    /// Overrides per service name or alias.
+    #[serde(default)]
+    pub services: HashMap<String, ServiceOverride>,
+}
+
+/// Config without any required tags. Other fields use the same defaults as
+/// deserializing empty config.
+impl Default for UserConfig {
+    fn default() -> Self {
+        Self {
+            required_tags: Vec::new(),
+            forbidden_tags: Vec::new(),
+            tag_filter: None,
+            select_method: SelectMethod::default(),
+            latency_exponent: default_latency_exponent(),
+            min_uptime: 0.0,
+            sticky_seed: None,
+            ignore_fallback_warning: false,
+            ignore_replacement_notice: false,
+            preferred_instances: Vec::new(),
+            custom_instances: HashMap::new(),
+            blocked_instances: Vec::new(),
+            group_preferences: HashMap::new(),
+            services: HashMap::new(),
+        }
+    }
 }
 
 impl UserConfig {
+    /// Custom instance URLs of the service, under its name or any alias.
+    pub fn custom_instances_for<'a>(
+        &'a self,
+        service: &'a Service,
+    ) -> impl Iterator<Item = &'a Url> {
+        std::iter::once(&service.name)
+            .chain(&service.aliases)
+            .filter_map(|key| self.custom_instances.get(key))
+            .flatten()
+    }
+
+    /// Check if instance host is blocked by user.
+    pub fn is_blocked(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        self.blocked_instances.iter().any(|blocked| blocked == host)
+    }
+
+    /// User config with overrides for the service merged over global settings.
+    ///
+    /// Overrides keyed by service name win over ones keyed by alias.
+    pub fn for_service(&self, service: &Service) -> Cow<'_, UserConfig> {
+        let Some(overrides) = std::iter::once(&service.name)
+            .chain(&service.aliases)
+            .find_map(|key| self.services.get(key))
+        else {
+            return Cow::Borrowed(self);
+        };
+        let mut config = self.clone();
+        if let Some(required_tags) = &overrides.required_tags {
+            config.required_tags = required_tags.clone();
+        }
+        if let Some(forbidden_tags) = &overrides.forbidden_tags {
+            config.forbidden_tags = forbidden_tags.clone();
+        }
+        if let Some(tag_filter) = &overrides.tag_filter {
+            config.tag_filter = Some(tag_filter.clone());
+        }
+        if let Some(select_method) = &overrides.select_method {
+            config.select_method = select_method.clone();
+        }
+        if let Some(preferred_instances) = &overrides.preferred_instances {
+            config.preferred_instances = preferred_instances.clone();
+        }
+        if let Some(ignore_fallback_warning) = overrides.ignore_fallback_warning {
+            config.ignore_fallback_warning = ignore_fallback_warning;
+        }
+        Cow::Owned(config)
+    }
+
+    /// Config with serde defaults of all fields, as if deserialized from `{}`.
+    /// Unlike [`Default`], it requires the default `clearnet`, `https` and
+    /// `ipv4` tags.
+    pub fn serde_default() -> Self {
+        serde_json::from_str("{}").expect("empty user config must deserialize")
+    }
+
+    /// Encode config string, safe to embed in URL paths and query parameters.
+    /// See [`crate::config_string`].
     pub fn to_config_string(&self) -> Result<String, UserConfigError> {
-        use base64::prelude::*;
-        let json: String = serde_json::to_string(&self).map_err(UserConfigError::Serialization)?;
-        Ok(BASE64_STANDARD.encode(json.as_bytes()))
+        config_string::encode(self)
     }
 
+    /// Parse config string of any supported version.
     pub fn from_config_string(data: &str) -> Result<Self, UserConfigError> {
-        use base64::prelude::*;
-        let decoded = BASE64_STANDARD.decode(data.as_bytes())?;
-        let json = String::from_utf8(decoded).unwrap();
-        serde_json::from_str(&json).map_err(UserConfigError::from)
+        config_string::decode(data)
     }
 }
 
+/// Which query parameters win when both redirect URL and request have them.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum QueryPrecedence {
+    /// Parameters from the incoming request override template ones.
+    #[default]
+    Request,
+    /// Parameters produced by the URL template override request ones.
+    Template,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AutoUpdaterConfig {
     pub enabled: bool,
@@ -124,6 +353,90 @@ impl Default for AutoUpdaterConfig {
     }
 }
 
+const fn default_load_half_life() -> Duration {
+    Duration::from_secs(60 * 10)
+}
+
+/// Configuration of redirect counters used by [`SelectMethod::LeastLoaded`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoadConfig {
+    /// Time after which redirect counts are halved.
+    #[serde(default = "default_load_half_life")]
+    pub half_life: Duration,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        Self {
+            half_life: default_load_half_life(),
+        }
+    }
+}
+
+/// GeoIP configuration.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct GeoIpConfig {
+    /// Path to MaxMind-format `.mmdb` database. GeoIP is disabled if not set.
+    #[serde(default)]
+    pub database: Option<PathBuf>,
+    /// Headers with client IP set by trusted reverse proxies, checked in order,
+    /// e.g. `X-Forwarded-For`. The last address of a list is used. Only list
+    /// headers the proxy always sets or appends to, otherwise clients can
+    /// spoof their address. Peer address is used if none is present.
+    #[serde(default)]
+    pub trusted_headers: Vec<String>,
+}
+
+const fn default_short_code_ttl() -> Duration {
+    // 1 year
+    Duration::from_secs(60 * 60 * 24 * 365)
+}
+
+const fn default_max_short_code_config_size() -> usize {
+    8 * 1024
+}
+
+const fn default_max_short_codes() -> usize {
+    100_000
+}
+
+const fn default_max_short_code_creates_per_minute() -> u32 {
+    60
+}
+
+/// Server-side storage of user configs under short codes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShortCodesConfig {
+    /// Path to store file. Short codes are disabled if not set.
+    #[serde(default)]
+    pub store: Option<PathBuf>,
+    /// Time after which stored config expires. Storing the same config again
+    /// renews it.
+    #[serde(default = "default_short_code_ttl")]
+    pub ttl: Duration,
+    /// Maximum length of stored config string in bytes.
+    #[serde(default = "default_max_short_code_config_size")]
+    pub max_config_size: usize,
+    /// Maximum number of stored configs.
+    #[serde(default = "default_max_short_codes")]
+    pub max_codes: usize,
+    /// Maximum number of new codes created per minute across all clients.
+    #[serde(default = "default_max_short_code_creates_per_minute")]
+    pub max_creates_per_minute: u32,
+}
+
+impl Default for ShortCodesConfig {
+    fn default() -> Self {
+        Self {
+            store: None,
+            ttl: default_short_code_ttl(),
+            max_config_size: default_max_short_code_config_size(),
+            max_codes: default_max_short_codes(),
+            max_creates_per_minute: default_max_short_code_creates_per_minute(),
+        }
+    }
+}
+
 /// Application configuration.
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct AppConfig {
@@ -137,6 +450,14 @@ pub struct AppConfig {
     pub default_user_config: UserConfig,
     #[serde(default)]
     pub services: Option<String>,
+    #[serde(default)]
+    pub query_precedence: QueryPrecedence,
+    #[serde(default)]
+    pub geoip: GeoIpConfig,
+    #[serde(default)]
+    pub load: LoadConfig,
+    #[serde(default)]
+    pub short_codes: ShortCodesConfig,
 }
 
 /// Load application configuration.
@@ -170,3 +491,65 @@ pub fn load_config(config_path: &Option<PathBuf>) -> Result<AppConfig> {
 
     Ok(app)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_config_defaults() {
+        let parsed: UserConfig = serde_json::from_str("{}").unwrap();
+        let default = UserConfig::default();
+        assert_eq!(default.latency_exponent, parsed.latency_exponent);
+        assert!(default.required_tags.is_empty());
+        assert_eq!(
+            parsed.required_tags,
+            UserConfig::serde_default().required_tags
+        );
+
+        let parse = |exponent: &str| {
+            serde_json::from_str::<UserConfig>(&format!(r#"{{"latency_exponent": {exponent}}}"#))
+                .map(|config| config.latency_exponent)
+        };
+        assert_eq!(parse("2.5").unwrap(), 2.5);
+        assert_eq!(parse("-1").unwrap(), 0.0);
+        assert_eq!(parse("1e300").unwrap(), MAX_LATENCY_EXPONENT);
+        // JSON has no NaN or infinity, other config formats do.
+        let parse_f64 = |exponent: f64| {
+            deserialize_latency_exponent(
+                serde::de::value::F64Deserializer::<serde::de::value::Error>::new(exponent),
+            )
+        };
+        assert!(parse_f64(f64::NAN).is_err());
+        assert_eq!(parse_f64(f64::INFINITY).unwrap(), MAX_LATENCY_EXPONENT);
+    }
+
+    #[test]
+    fn test_service_overrides() {
+        let service: Service =
+            serde_json::from_str(r#"{"type": "nitter", "aliases": ["twitter"], "instances": []}"#)
+                .unwrap();
+        let other: Service =
+            serde_json::from_str(r#"{"type": "invidious", "instances": []}"#).unwrap();
+        let config: UserConfig = serde_json::from_str(
+            r#"{
+                "select_method": "LowPing",
+                "services": {
+                    "twitter": {"required_tags": ["tor"], "select_method": "Sticky"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let merged = config.for_service(&service);
+        assert_eq!(merged.required_tags, vec!["tor"]);
+        assert_eq!(merged.select_method, SelectMethod::Sticky);
+        // Fields without override are kept.
+        assert_eq!(merged.forbidden_tags, config.forbidden_tags);
+
+        let merged = config.for_service(&other);
+        assert!(matches!(merged, Cow::Borrowed(_)));
+        assert_eq!(merged.required_tags, default_required_tags());
+        assert_eq!(merged.select_method, SelectMethod::LowPing);
+    }
+}
@@ -0,0 +1,200 @@
+//! Encoding of [`UserConfig`] into config strings kept in cookies and URLs.
+//!
+//! Config string is URL-safe base64 without padding of a version byte followed
+//! by raw deflate of JSON body. Fields equal to their serde defaults are left
+//! out of the body, so changing a default changes meaning of old strings and
+//! needs a migration.
+//!
+//! Version 1 has uncompressed JSON body. Version 0 is the legacy format:
+//! standard or URL-safe base64 of bare JSON, which always starts with `{`.
+//! Bodies of older versions are upgraded with [`MIGRATIONS`] before
+//! deserializing. Unknown fields are ignored.
+
+use std::io::Read;
+
+use base64::prelude::*;
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use serde_json::{Map, Value};
+
+use crate::{config::UserConfig, errors::UserConfigError};
+
+/// Version of config strings produced by [`encode`].
+pub const CURRENT_VERSION: u8 = 2;
+
+/// First version with compressed body.
+const DEFLATE_VERSION: u8 = 2;
+
+/// Maximum size of decompressed body.
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// First byte of version 0 strings, which have no version byte.
+const LEGACY_JSON_START: u8 = b'{';
+
+/// Migrations of JSON body, `MIGRATIONS[n]` upgrades version `n` to `n + 1`.
+const MIGRATIONS: [fn(&mut Map<String, Value>); CURRENT_VERSION as usize] = [migrate_v0, |_| {}];
+
+/// Version 0 configure page saved unset fields as `null` and unset select
+/// method and tag filter as empty strings.
+fn migrate_v0(body: &mut Map<String, Value>) {
+    body.retain(|_, value| !value.is_null() && value != "");
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>, UserConfigError> {
+    let data = data.trim_end_matches('=');
+    BASE64_URL_SAFE_NO_PAD
+        .decode(data)
+        .or_else(|e| BASE64_STANDARD_NO_PAD.decode(data).map_err(|_| e))
+        .map_err(UserConfigError::from)
+}
+
+/// Split decoded config string into version and JSON body.
+fn split_version(bytes: &[u8]) -> Result<(u8, &[u8]), UserConfigError> {
+    match bytes.split_first() {
+        None => Err(UserConfigError::Empty),
+        Some((&LEGACY_JSON_START, _)) => Ok((0, bytes)),
+        Some((&version, body)) if version <= CURRENT_VERSION => Ok((version, body)),
+        Some((&version, _)) => Err(UserConfigError::UnsupportedVersion(version)),
+    }
+}
+
+/// Version of config string.
+pub fn version(data: &str) -> Result<u8, UserConfigError> {
+    split_version(&decode_base64(data)?).map(|(version, _)| version)
+}
+
+pub fn encode(config: &UserConfig) -> Result<String, UserConfigError> {
+    let Value::Object(defaults) = serde_json::to_value(UserConfig::serde_default())? else {
+        unreachable!("user config is serialized as object");
+    };
+    let Value::Object(mut body) = serde_json::to_value(config)? else {
+        unreachable!("user config is serialized as object");
+    };
+    body.retain(|key, value| defaults.get(key) != Some(value));
+
+    let mut encoder = DeflateEncoder::new(vec![CURRENT_VERSION], Compression::best());
+    serde_json::to_writer(&mut encoder, &body)?;
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(encoder.finish()?))
+}
+
+/// Decompress body, bounding its size against decompression bombs.
+fn inflate(body: &[u8]) -> Result<Vec<u8>, UserConfigError> {
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(body)
+        .take(MAX_BODY_SIZE + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() as u64 > MAX_BODY_SIZE {
+        return Err(UserConfigError::TooLarge(MAX_BODY_SIZE));
+    }
+    Ok(inflated)
+}
+
+pub fn decode(data: &str) -> Result<UserConfig, UserConfigError> {
+    let bytes = decode_base64(data)?;
+    let (version, body) = split_version(&bytes)?;
+    let inflated;
+    let body = if version >= DEFLATE_VERSION {
+        inflated = inflate(body)?;
+        &inflated
+    } else {
+        body
+    };
+    let body = std::str::from_utf8(body)?;
+    let Value::Object(mut body) = serde_json::from_str(body)? else {
+        return Err(UserConfigError::Serialization(serde::de::Error::custom(
+            "config body is not an object",
+        )));
+    };
+    for migrate in &MIGRATIONS[version as usize..] {
+        migrate(&mut body);
+    }
+    Ok(serde_json::from_value(Value::Object(body))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::config::SelectMethod;
+
+    #[test]
+    fn test_roundtrip() {
+        let default = encode(&UserConfig::serde_default()).unwrap();
+        let bytes = BASE64_URL_SAFE_NO_PAD.decode(&default).unwrap();
+        assert_eq!(bytes[0], CURRENT_VERSION);
+        assert_eq!(inflate(&bytes[1..]).unwrap(), b"{}");
+
+        let config = UserConfig {
+            select_method: SelectMethod::LowPing,
+            required_tags: vec!["tor".to_string()],
+            ..UserConfig::serde_default()
+        };
+        let encoded = encode(&config).unwrap();
+        assert_eq!(version(&encoded).unwrap(), CURRENT_VERSION);
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded.select_method, SelectMethod::LowPing);
+        assert_eq!(decoded.required_tags, vec!["tor"]);
+        assert_eq!(decoded.latency_exponent, 1.0);
+
+        // Non-ASCII strings survive the round trip.
+        let config = UserConfig {
+            preferred_instances: vec!["https://bücher.example/".to_string()],
+            ..UserConfig::serde_default()
+        };
+        let decoded = decode(&encode(&config).unwrap()).unwrap();
+        assert_eq!(decoded.preferred_instances, config.preferred_instances);
+    }
+
+    #[test]
+    fn test_v1() {
+        let v1 = BASE64_URL_SAFE_NO_PAD.encode(b"\x01{\"required_tags\":[\"tor\"]}");
+        assert_eq!(version(&v1).unwrap(), 1);
+        assert_eq!(decode(&v1).unwrap().required_tags, vec!["tor"]);
+    }
+
+    #[test]
+    fn test_legacy() {
+        let json = r#"{"required_tags":["https"],"select_method":"","tag_filter":"","sticky_seed":null,"unknown":1}"#;
+        for legacy in [
+            BASE64_STANDARD.encode(json),
+            BASE64_URL_SAFE_NO_PAD.encode(json),
+        ] {
+            assert_eq!(version(&legacy).unwrap(), 0);
+            let decoded = decode(&legacy).unwrap();
+            assert_eq!(decoded.required_tags, vec!["https"]);
+            assert_eq!(decoded.select_method, SelectMethod::Random);
+            assert!(decoded.tag_filter.is_none());
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(decode(""), Err(UserConfigError::Empty)));
+        assert!(matches!(
+            decode("!!"),
+            Err(UserConfigError::Base64Decode(_))
+        ));
+        let future = BASE64_URL_SAFE_NO_PAD.encode(b"\x7f{}");
+        assert!(matches!(
+            decode(&future),
+            Err(UserConfigError::UnsupportedVersion(0x7f))
+        ));
+        let invalid_utf8 = BASE64_URL_SAFE_NO_PAD.encode(b"\x01{\xff}");
+        assert!(matches!(
+            decode(&invalid_utf8),
+            Err(UserConfigError::Utf8(_))
+        ));
+        let not_object = BASE64_URL_SAFE_NO_PAD.encode(b"\x01[]");
+        assert!(matches!(
+            decode(&not_object),
+            Err(UserConfigError::Serialization(_))
+        ));
+        let not_deflate = BASE64_URL_SAFE_NO_PAD.encode(b"\x02{}");
+        assert!(matches!(decode(&not_deflate), Err(UserConfigError::Io(_))));
+
+        let mut bomb = DeflateEncoder::new(vec![CURRENT_VERSION], Compression::best());
+        bomb.write_all(&[b' '; MAX_BODY_SIZE as usize + 1]).unwrap();
+        let bomb = BASE64_URL_SAFE_NO_PAD.encode(bomb.finish().unwrap());
+        assert!(matches!(decode(&bomb), Err(UserConfigError::TooLarge(_))));
+    }
+}
@@ -12,6 +12,16 @@ pub enum CliError {
 pub enum UserConfigError {
     #[error("serialization error: `{0}`")]
     Serialization(#[from] serde_json::Error),
-    #[error("urlencode error: `{0}`")]
+    #[error("base64 decode error: `{0}`")]
     Base64Decode(#[from] base64::DecodeError),
+    #[error("config is not valid UTF-8: `{0}`")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("config string is empty")]
+    Empty,
+    #[error("config string version {0} is not supported, newer Fastside is needed")]
+    UnsupportedVersion(u8),
+    #[error("failed to decompress config: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("config is too large, maximum is {0} bytes")]
+    TooLarge(u64),
 }
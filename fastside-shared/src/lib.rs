@@ -1,9 +1,15 @@
 pub mod client_builder;
 pub mod config;
+pub mod config_string;
 pub mod errors;
 pub mod log_setup;
+pub mod matcher;
 pub mod parallel;
+pub mod regex_samples;
 pub mod serde_types;
+pub mod tag_filter;
+pub mod url_template;
+pub mod url_utils;
 
 #[macro_use]
 extern crate log;
@@ -0,0 +1,534 @@
+//! URL matcher.
+//!
+//! Matches URL against regexes of every service in a single pass and maps
+//! instance hosts back to their services.
+
+use std::collections::{HashMap, HashSet};
+
+use regex::{Captures, Regex, RegexSet, RegexSetBuilder};
+use url::Url;
+
+use crate::{
+    serde_types::{RegexSearch, Service, ServiceGroup},
+    url_template::{ReplaceArgsError, UrlTemplate},
+};
+
+/// Size limit of combined regex set. Regexes which compile one by one may
+/// still be too large together.
+const REGEX_SET_SIZE_LIMIT: usize = 16 * (1 << 20);
+
+#[derive(Debug)]
+pub struct MatcherEntry {
+    pub service_name: String,
+    pub regex: Regex,
+    pub url: String,
+    pub template: UrlTemplate,
+    pub priority: i32,
+    pub drop_query: bool,
+}
+
+#[derive(Debug)]
+pub struct UrlMatcher {
+    set: RegexSet,
+    entries: Vec<MatcherEntry>,
+}
+
+impl UrlMatcher {
+    /// Build matcher from regexes and domains of all services.
+    ///
+    /// Invalid regexes and URL templates are logged and skipped. Entries are ordered by service
+    /// name and then by declaration order, which makes tie breaking stable. Fails if combined
+    /// regex set is too large.
+    pub fn new<'a>(services: impl IntoIterator<Item = &'a Service>) -> Result<Self, regex::Error> {
+        let searches: Vec<(&str, Vec<RegexSearch>)> = services
+            .into_iter()
+            .map(|service| (service.name.as_str(), service.url_searches()))
+            .collect();
+        Self::from_searches(
+            searches
+                .iter()
+                .map(|(name, searches)| (*name, searches.as_slice())),
+        )
+    }
+
+    /// Build matcher from (service name, regexes) pairs.
+    fn from_searches<'a>(
+        searches: impl IntoIterator<Item = (&'a str, &'a [RegexSearch])>,
+    ) -> Result<Self, regex::Error> {
+        let mut searches: Vec<(&str, &[RegexSearch])> = searches.into_iter().collect();
+        searches.sort_by_key(|(name, _)| *name);
+
+        let mut entries = Vec::new();
+        for (name, searches) in searches {
+            for search in searches {
+                let regex = match Regex::new(&search.regex) {
+                    Ok(regex) => regex,
+                    Err(e) => {
+                        error!("Failed to compile regex for {}: {}", name, e);
+                        continue;
+                    }
+                };
+                let template = match UrlTemplate::parse(&search.url) {
+                    Ok(template) => template,
+                    Err(e) => {
+                        error!("Failed to parse URL template for {}: {}", name, e);
+                        continue;
+                    }
+                };
+                entries.push(MatcherEntry {
+                    service_name: name.to_string(),
+                    regex,
+                    url: search.url.clone(),
+                    template,
+                    priority: search.priority,
+                    drop_query: search.drop_query,
+                });
+            }
+        }
+
+        let set = RegexSetBuilder::new(entries.iter().map(|e| e.regex.as_str()))
+            .size_limit(REGEX_SET_SIZE_LIMIT)
+            .dfa_size_limit(REGEX_SET_SIZE_LIMIT)
+            .build()?;
+
+        Ok(Self { set, entries })
+    }
+
+    /// Find entry matching the query.
+    ///
+    /// If several entries match, entry with the highest priority wins. On equal
+    /// priority the first entry wins.
+    pub fn find<'h>(&self, query: &'h str) -> Option<(&MatcherEntry, Captures<'h>)> {
+        let entry = self
+            .set
+            .matches(query)
+            .iter()
+            .min_by_key(|&i| (std::cmp::Reverse(self.entries[i].priority), i))
+            .map(|i| &self.entries[i])?;
+        let captures = entry.regex.captures(query)?;
+        Some((entry, captures))
+    }
+
+    /// Find entry matching the query and substitute captures into its URL template.
+    pub fn rewrite(
+        &self,
+        query: &str,
+    ) -> Option<Result<(&MatcherEntry, String), ReplaceArgsError>> {
+        let (entry, captures) = self.find(query)?;
+        Some(
+            entry
+                .template
+                .render(query, &captures)
+                .map(|url| (entry, url)),
+        )
+    }
+}
+
+/// Maps paths on service instances back to original URLs.
+///
+/// Uses `reverse_regexes` of services, matched against path with query
+/// relative to instance base path.
+#[derive(Debug, Default)]
+pub struct ReverseMatcher {
+    services: HashMap<String, UrlMatcher>,
+}
+
+impl ReverseMatcher {
+    pub fn new<'a>(services: impl IntoIterator<Item = &'a Service>) -> Result<Self, regex::Error> {
+        let services = services
+            .into_iter()
+            .filter(|service| !service.reverse_regexes.is_empty())
+            .map(|service| {
+                let matcher = UrlMatcher::from_searches([(
+                    service.name.as_str(),
+                    service.reverse_regexes.as_slice(),
+                )])?;
+                Ok((service.name.clone(), matcher))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { services })
+    }
+
+    /// Get original URL for `path` on instance of service `service_name`.
+    pub fn rewrite(
+        &self,
+        service_name: &str,
+        path: &str,
+    ) -> Option<Result<String, ReplaceArgsError>> {
+        let rewritten = self.services.get(service_name)?.rewrite(path)?;
+        Some(rewritten.map(|(_, url)| url))
+    }
+}
+
+/// Maps paths of replaced services to paths on their replacements.
+///
+/// Uses `replacement_regexes` of services, matched against path with query.
+#[derive(Debug, Default)]
+pub struct ReplacementMatcher {
+    services: HashMap<String, UrlMatcher>,
+}
+
+impl ReplacementMatcher {
+    pub fn new<'a>(services: impl IntoIterator<Item = &'a Service>) -> Result<Self, regex::Error> {
+        let services = services
+            .into_iter()
+            .filter(|service| !service.replacement_regexes.is_empty())
+            .map(|service| {
+                let matcher = UrlMatcher::from_searches([(
+                    service.name.as_str(),
+                    service.replacement_regexes.as_slice(),
+                )])?;
+                Ok((service.name.clone(), matcher))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { services })
+    }
+
+    /// Get path on replacement of service `service_name`.
+    pub fn rewrite(
+        &self,
+        service_name: &str,
+        path: &str,
+    ) -> Option<Result<String, ReplaceArgsError>> {
+        let rewritten = self.services.get(service_name)?.rewrite(path)?;
+        Some(rewritten.map(|(_, url)| url))
+    }
+}
+
+/// Compiled service group.
+#[derive(Debug)]
+pub struct CompiledGroup {
+    pub name: String,
+    /// Member service names in group order.
+    pub services: Vec<String>,
+    rewrites: HashMap<String, UrlMatcher>,
+}
+
+impl CompiledGroup {
+    /// Rewrite original URL to path on instance of group member `service_name`.
+    pub fn rewrite(
+        &self,
+        service_name: &str,
+        original: &str,
+    ) -> Option<Result<String, ReplaceArgsError>> {
+        let rewritten = self.rewrites.get(service_name)?.rewrite(original)?;
+        Some(rewritten.map(|(_, url)| url))
+    }
+}
+
+/// Service groups indexed by name and by member service.
+#[derive(Debug, Default)]
+pub struct GroupIndex {
+    groups: HashMap<String, CompiledGroup>,
+    /// Member service name to group name. First group wins.
+    by_service: HashMap<String, String>,
+}
+
+impl GroupIndex {
+    pub fn new<'a>(
+        groups: impl IntoIterator<Item = &'a ServiceGroup>,
+        services: impl IntoIterator<Item = &'a Service>,
+    ) -> Result<Self, regex::Error> {
+        let services: HashMap<&str, &Service> =
+            services.into_iter().map(|s| (s.name.as_str(), s)).collect();
+        let mut index = Self::default();
+        for group in groups {
+            let mut rewrites = HashMap::new();
+            for member in &group.services {
+                let Some(searches) = member.url_searches(&services) else {
+                    continue;
+                };
+                let matcher =
+                    UrlMatcher::from_searches([(member.service.as_str(), searches.as_slice())])?;
+                rewrites.insert(member.service.clone(), matcher);
+                index
+                    .by_service
+                    .entry(member.service.clone())
+                    .or_insert_with(|| group.name.clone());
+            }
+            index.groups.insert(
+                group.name.clone(),
+                CompiledGroup {
+                    name: group.name.clone(),
+                    services: group.services.iter().map(|m| m.service.clone()).collect(),
+                    rewrites,
+                },
+            );
+        }
+        Ok(index)
+    }
+
+    /// Get group by name.
+    pub fn get(&self, name: &str) -> Option<&CompiledGroup> {
+        self.groups.get(name)
+    }
+
+    /// Get group the service belongs to.
+    pub fn for_service(&self, service_name: &str) -> Option<&CompiledGroup> {
+        self.groups.get(self.by_service.get(service_name)?)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &CompiledGroup> {
+        self.groups.values()
+    }
+}
+
+/// Hosts declared in `domains` of services.
+#[derive(Debug, Default)]
+pub struct DomainIndex {
+    hosts: HashSet<String>,
+    /// Wildcard domains with leading dot, e.g. `.example.com`.
+    suffixes: Vec<String>,
+}
+
+impl DomainIndex {
+    pub fn new<'a>(services: impl IntoIterator<Item = &'a Service>) -> Self {
+        let mut index = Self::default();
+        for rule in services.into_iter().flat_map(|service| &service.domains) {
+            let domain = rule.domain.to_lowercase();
+            match domain.strip_prefix('*') {
+                Some(suffix) => index.suffixes.push(suffix.to_string()),
+                None => {
+                    index.hosts.insert(domain);
+                }
+            }
+        }
+        index
+    }
+
+    /// Check if host is declared by any service.
+    pub fn contains(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.hosts.contains(&host)
+            || self
+                .suffixes
+                .iter()
+                .any(|suffix| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+    }
+}
+
+/// Maps hosts of known instances to their services.
+#[derive(Debug, Default)]
+pub struct InstanceIndex {
+    /// Host to list of (service name, instance base path), sorted by base path
+    /// length, longest first.
+    hosts: HashMap<String, Vec<(String, String)>>,
+}
+
+impl InstanceIndex {
+    pub fn new<'a>(services: impl IntoIterator<Item = &'a Service>) -> Self {
+        let mut services: Vec<&Service> = services.into_iter().collect();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut hosts: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        for service in services {
+            for instance in &service.instances {
+                let Some(host) = instance.url.host_str() else {
+                    continue;
+                };
+                let mut base_path = instance.url.path().to_string();
+                if !base_path.ends_with('/') {
+                    base_path.push('/');
+                }
+                let entries = hosts.entry(host.to_string()).or_default();
+                if !entries.contains(&(service.name.clone(), base_path.clone())) {
+                    entries.push((service.name.clone(), base_path));
+                }
+            }
+        }
+        for entries in hosts.values_mut() {
+            entries.sort_by_key(|(_, base_path)| std::cmp::Reverse(base_path.len()));
+        }
+
+        Self { hosts }
+    }
+
+    /// Check if host belongs to any known instance. Host is compared
+    /// case-insensitively.
+    pub fn contains_host(&self, host: &str) -> bool {
+        self.hosts.contains_key(&host.to_lowercase())
+    }
+
+    /// Find service owning instance at `url`.
+    ///
+    /// Returns service name and path with query relative to instance base path.
+    pub fn find(&self, url: &Url) -> Option<(&str, String)> {
+        let entries = self.hosts.get(url.host_str()?)?;
+        let path = url.path();
+        let (service_name, base_path) = entries.iter().find(|(_, base_path)| {
+            path.starts_with(base_path.as_str()) || format!("{path}/") == *base_path
+        })?;
+        let mut relative = format!("/{}", path.get(base_path.len()..).unwrap_or_default());
+        if let Some(query) = url.query() {
+            relative.push('?');
+            relative.push_str(query);
+        }
+        Some((service_name, relative))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serde_types::StoredData;
+
+    fn matcher(json: &str) -> UrlMatcher {
+        let stored: StoredData = serde_json::from_str(json).unwrap();
+        UrlMatcher::new(&stored.services).unwrap()
+    }
+
+    #[test]
+    fn test_set_size_limit() {
+        // Every regex fits default size limit of a single regex, but not all
+        // of them together.
+        let searches: Vec<RegexSearch> = (0..4)
+            .map(|i| RegexSearch {
+                regex: format!(r"^{i}\w{{100}}$"),
+                url: "/".to_string(),
+                priority: 0,
+                drop_query: false,
+                examples: Vec::new(),
+            })
+            .collect();
+        assert!(UrlMatcher::from_searches([("a", &searches[..1])]).is_ok());
+        assert!(UrlMatcher::from_searches([("a", searches.as_slice())]).is_err());
+    }
+
+    #[test]
+    fn test_priority() {
+        let matcher = matcher(
+            r#"{"services": [
+                {"type": "a", "instances": [], "regexes": [{"regex": "^example\\.com/(.*)", "url": "/$1"}]},
+                {"type": "b", "instances": [], "regexes": [{"regex": "^example\\.com/(.*)", "url": "/b/$1", "priority": 1}]}
+            ]}"#,
+        );
+        let (entry, _) = matcher.find("example.com/test").unwrap();
+        assert_eq!(entry.service_name, "b");
+    }
+
+    #[test]
+    fn test_equal_priority_is_stable() {
+        let matcher = matcher(
+            r#"{"services": [
+                {"type": "b", "instances": [], "regexes": [{"regex": "^example\\.com/(.*)", "url": "/$1"}]},
+                {"type": "a", "instances": [], "regexes": [{"regex": "^example\\.com/(.*)", "url": "/$1"}]}
+            ]}"#,
+        );
+        let (entry, _) = matcher.find("example.com/test").unwrap();
+        assert_eq!(entry.service_name, "a");
+        assert!(matcher.find("example.org/test").is_none());
+    }
+
+    #[test]
+    fn test_instance_index() {
+        let stored: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": [{"url": "https://example.org/", "tags": []}]},
+                {"type": "b", "instances": [{"url": "https://example.org/b/", "tags": []}]}
+            ]}"#,
+        )
+        .unwrap();
+        let index = InstanceIndex::new(&stored.services);
+
+        let url = Url::parse("https://example.org/watch?v=x").unwrap();
+        assert_eq!(index.find(&url), Some(("a", "/watch?v=x".to_string())));
+        let url = Url::parse("https://example.org/b/r/rust").unwrap();
+        assert_eq!(index.find(&url), Some(("b", "/r/rust".to_string())));
+        let url = Url::parse("https://example.org/b").unwrap();
+        assert_eq!(index.find(&url), Some(("b", "/".to_string())));
+        let url = Url::parse("https://example.com/").unwrap();
+        assert_eq!(index.find(&url), None);
+
+        assert!(index.contains_host("example.org"));
+        assert!(index.contains_host("Example.ORG"));
+        assert!(!index.contains_host("example.com"));
+    }
+
+    #[test]
+    fn test_reverse_matcher() {
+        let stored: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": [], "reverse_regexes": [
+                    {"regex": "^/watch\\?v=([^&]+)", "url": "https://example.com/watch?v=$1", "priority": 1},
+                    {"regex": "^(/.*)$", "url": "https://example.com$1"}
+                ]},
+                {"type": "b", "instances": []}
+            ]}"#,
+        )
+        .unwrap();
+        let reverse = ReverseMatcher::new(&stored.services).unwrap();
+        assert_eq!(
+            reverse.rewrite("a", "/watch?v=x&t=1").unwrap().unwrap(),
+            "https://example.com/watch?v=x"
+        );
+        assert_eq!(
+            reverse.rewrite("a", "/feed").unwrap().unwrap(),
+            "https://example.com/feed"
+        );
+        assert!(reverse.rewrite("b", "/feed").is_none());
+    }
+
+    #[test]
+    fn test_group_index() {
+        let stored: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": [], "regexes": [{"regex": "^example\\.com(/.*)", "url": "$1"}]},
+                {"type": "b", "instances": []}
+            ], "groups": [
+                {"name": "example", "services": [
+                    {"service": "a"},
+                    {"service": "b", "regexes": [{"regex": "^example\\.com(/.*)", "url": "/b$1"}]}
+                ]}
+            ]}"#,
+        )
+        .unwrap();
+        let index = GroupIndex::new(&stored.groups, &stored.services).unwrap();
+        let group = index.for_service("b").unwrap();
+        assert_eq!(group.name, "example");
+        assert_eq!(group.services, vec!["a", "b"]);
+        assert_eq!(group.rewrite("a", "example.com/x").unwrap().unwrap(), "/x");
+        assert_eq!(
+            group.rewrite("b", "example.com/x").unwrap().unwrap(),
+            "/b/x"
+        );
+        assert!(index.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_domains() {
+        let stored: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": [], "domains": [
+                    {"domain": "example.com"},
+                    {"domain": "*.example.com", "paths": [{"from": "/u/", "to": "/user/"}]}
+                ]}
+            ]}"#,
+        )
+        .unwrap();
+        let matcher = UrlMatcher::new(&stored.services).unwrap();
+        let rewrite = |query: &str| matcher.rewrite(query).map(|r| r.unwrap().1);
+        assert_eq!(rewrite("https://example.com/r/rust").unwrap(), "/r/rust");
+        assert_eq!(rewrite("example.com").unwrap(), "/");
+        assert_eq!(
+            rewrite("old.example.com/r/rust?x=1").unwrap(),
+            "/r/rust?x=1"
+        );
+        assert_eq!(
+            rewrite("https://a.b.example.com:443/u/x").unwrap(),
+            "/user/x"
+        );
+        assert_eq!(
+            rewrite("HTTPS://Old.Example.COM/u/Rust").unwrap(),
+            "/user/Rust"
+        );
+        // Paths stay case-sensitive.
+        assert_eq!(rewrite("OLD.EXAMPLE.COM/U/x").unwrap(), "/U/x");
+        assert!(rewrite("https://notexample.com/").is_none());
+        assert!(rewrite("https://example.com.evil/").is_none());
+
+        let index = DomainIndex::new(&stored.services);
+        assert!(index.contains("example.com"));
+        assert!(index.contains("Old.Example.com"));
+        assert!(!index.contains("notexample.com"));
+    }
+}
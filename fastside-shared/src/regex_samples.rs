@@ -0,0 +1,145 @@
+//! Generate sample strings matching a regex.
+//!
+//! Used by services file validation to find regexes of different services
+//! that can match the same URL.
+
+use regex_syntax::hir::{Class, Hir, HirKind};
+
+/// Preferred characters to pick from a character class, in order.
+const PREFERRED_CHARS: &[char] = &['a', 'A', '0', '-', '/'];
+
+fn pick_unicode_char(ranges: &[regex_syntax::hir::ClassUnicodeRange]) -> Option<char> {
+    let contains = |c: char| ranges.iter().any(|r| r.start() <= c && c <= r.end());
+    PREFERRED_CHARS
+        .iter()
+        .copied()
+        .find(|c| contains(*c))
+        .or_else(|| ('!'..='~').find(|c| contains(*c)))
+        .or_else(|| ranges.first().map(|r| r.start()))
+}
+
+fn pick_byte(ranges: &[regex_syntax::hir::ClassBytesRange]) -> Option<u8> {
+    let contains = |b: u8| ranges.iter().any(|r| r.start() <= b && b <= r.end());
+    PREFERRED_CHARS
+        .iter()
+        .map(|c| *c as u8)
+        .find(|b| contains(*b))
+        .or_else(|| (b'!'..=b'~').find(|b| contains(*b)))
+        .or_else(|| ranges.first().map(|r| r.start()))
+}
+
+/// Generate samples for `hir`.
+///
+/// The first sample takes the first branch of every alternation. Each other
+/// sample takes one other branch of a single alternation, so every branch is
+/// covered while the number of samples stays linear in the size of `hir`.
+///
+/// If `expand` is set, optional parts are included once, otherwise every
+/// repetition is taken minimal number of times.
+fn hir_samples(hir: &Hir, expand: bool) -> Vec<String> {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => vec![String::new()],
+        HirKind::Literal(literal) => vec![String::from_utf8_lossy(&literal.0).into_owned()],
+        HirKind::Class(Class::Unicode(class)) => {
+            vec![
+                pick_unicode_char(class.ranges())
+                    .map(String::from)
+                    .unwrap_or_default(),
+            ]
+        }
+        HirKind::Class(Class::Bytes(class)) => vec![
+            pick_byte(class.ranges())
+                .map(|b| String::from(b as char))
+                .unwrap_or_default(),
+        ],
+        HirKind::Repetition(repetition) => {
+            let count = if expand && repetition.max != Some(0) {
+                repetition.min.max(1)
+            } else {
+                repetition.min
+            };
+            if count == 0 {
+                return vec![String::new()];
+            }
+            hir_samples(&repetition.sub, expand)
+                .into_iter()
+                .map(|sample| sample.repeat(count as usize))
+                .collect()
+        }
+        HirKind::Capture(capture) => hir_samples(&capture.sub, expand),
+        HirKind::Concat(hirs) => {
+            let parts: Vec<Vec<String>> = hirs.iter().map(|hir| hir_samples(hir, expand)).collect();
+            let first: Vec<&str> = parts.iter().map(|samples| samples[0].as_str()).collect();
+            let mut samples = vec![first.concat()];
+            for (i, part) in parts.iter().enumerate() {
+                for variant in &part[1..] {
+                    let mut sample = first.clone();
+                    sample[i] = variant;
+                    samples.push(sample.concat());
+                }
+            }
+            samples
+        }
+        HirKind::Alternation(hirs) => hirs
+            .iter()
+            .flat_map(|hir| hir_samples(hir, expand))
+            .collect(),
+    }
+}
+
+/// Generate sample strings matching `pattern`.
+///
+/// Returns samples with every repetition taken minimal number of times, then
+/// with every optional part included once, each for every alternation branch.
+/// Samples that do not actually match the pattern (e.g. because of word
+/// boundaries) are dropped.
+pub fn regex_samples(pattern: &str) -> Result<Vec<String>, regex::Error> {
+    let regex = regex::Regex::new(pattern)?;
+    let hir = regex_syntax::parse(pattern).map_err(|e| regex::Error::Syntax(e.to_string()))?;
+
+    let mut samples = Vec::new();
+    for expand in [false, true] {
+        for sample in hir_samples(&hir, expand) {
+            if regex.is_match(&sample) && !samples.contains(&sample) {
+                samples.push(sample);
+            }
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples() {
+        let samples = regex_samples(r"^(https?:\/\/)?((www\.)?youtube\.com)(\/?.*)$").unwrap();
+        assert_eq!(samples, vec!["youtube.com", "https://www.youtube.com/a"]);
+    }
+
+    #[test]
+    fn test_samples_class() {
+        let samples = regex_samples(r"^(https?:\/\/)?youtu\.be/([^&]+)$").unwrap();
+        assert_eq!(samples, vec!["youtu.be/a", "https://youtu.be/a"]);
+    }
+
+    #[test]
+    fn test_samples_alternation() {
+        let samples = regex_samples(r"^(www\.)?(youtube|invidious\.io)\.com").unwrap();
+        assert_eq!(
+            samples,
+            vec![
+                "youtube.com",
+                "invidious.io.com",
+                "www.youtube.com",
+                "www.invidious.io.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(regex_samples(r"(").is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, fmt, vec};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, vec,
+};
 
 use serde::{
     Deserialize, Deserializer, Serialize, Serializer,
@@ -6,10 +9,26 @@ use serde::{
 };
 use url::Url;
 
+use crate::{
+    matcher::{GroupIndex, ReplacementMatcher, ReverseMatcher, UrlMatcher},
+    regex_samples::regex_samples,
+    url_template::{self, UrlTemplate},
+};
+
 #[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Instance {
     pub url: Url,
     pub tags: Vec<String>,
+    /// Relative capacity of instance, used to balance redirects across
+    /// instances. Default is 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+}
+
+impl Instance {
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
 }
 
 impl From<Url> for Instance {
@@ -17,18 +36,122 @@ impl From<Url> for Instance {
         Instance {
             url,
             tags: Vec::new(),
+            weight: None,
         }
     }
 }
 
+/// Names taken by redirect routes: `/c/<code>/` short code prefix.
+const RESERVED_NAMES: &[&str] = &["c"];
+/// Prefix taken by redirect routes: `/~<config>/` config string prefix.
+const RESERVED_PREFIX: char = '~';
+
+/// Check if service, alias or group name would be shadowed by a route.
+fn is_reserved_name(name: &str) -> bool {
+    RESERVED_NAMES.contains(&name) || name.starts_with(RESERVED_PREFIX)
+}
+
 fn default_test_url() -> String {
     "/".to_string()
 }
 
+/// Example input URL and expected redirect path, checked during validation.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RegexExample {
+    pub input: String,
+    pub expected: String,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RegexSearch {
     pub regex: String,
     pub url: String,
+    /// Regex with higher priority wins when several services match the same URL.
+    #[serde(default)]
+    pub priority: i32,
+    /// Do not merge query of matched URL into redirect URL. Set when
+    /// template picks query parameters itself.
+    #[serde(default)]
+    pub drop_query: bool,
+    #[serde(default)]
+    pub examples: Vec<RegexExample>,
+}
+
+/// Path prefix mapping of [`DomainRule`].
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PathMapping {
+    pub from: String,
+    pub to: String,
+}
+
+/// Host handled by the service, compiled into URL matcher without
+/// hand-written regexes.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DomainRule {
+    /// Hostname, or `*.example.com` to match any subdomain of `example.com`.
+    pub domain: String,
+    /// Path prefixes replaced on redirect. Paths matching none are kept as is.
+    #[serde(default)]
+    pub paths: Vec<PathMapping>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub examples: Vec<RegexExample>,
+}
+
+impl DomainRule {
+    /// Regex matching the domain with optional scheme and port. Scheme and
+    /// host are case-insensitive, as in [`crate::matcher::DomainIndex`].
+    ///
+    /// Letters are spelled as `[aA]` classes instead of `(?i)` flag, which
+    /// exported JavaScript regexes do not support.
+    fn host_regex(&self) -> String {
+        let host = match self.domain.strip_prefix("*.") {
+            Some(domain) => format!(
+                r"[^/?#@:.]+(?:\.[^/?#@:.]+)*\.{}",
+                ignore_ascii_case(&regex::escape(domain))
+            ),
+            None => ignore_ascii_case(&regex::escape(&self.domain)),
+        };
+        format!(r"^(?:[hH][tT][tT][pP][sS]?://)?{host}(?::[0-9]+)?")
+    }
+
+    /// Convert the rule to regexes. Path mappings go first, so they win over
+    /// the domain itself.
+    pub fn to_searches(&self) -> Vec<RegexSearch> {
+        let host = self.host_regex();
+        let mut searches: Vec<RegexSearch> = self
+            .paths
+            .iter()
+            .map(|mapping| RegexSearch {
+                regex: format!("{host}{}(?<rest>.*)$", regex::escape(&mapping.from)),
+                url: format!("{}${{rest}}", url_template::escape(&mapping.to)),
+                priority: self.priority,
+                drop_query: false,
+                examples: Vec::new(),
+            })
+            .collect();
+        searches.push(RegexSearch {
+            regex: format!("{host}(?<path>[/?].*)?$"),
+            url: "${path:-/}".to_string(),
+            priority: self.priority,
+            drop_query: false,
+            examples: self.examples.clone(),
+        });
+        searches
+    }
+}
+
+/// Replace ASCII letters of escaped regex literal with classes matching both
+/// cases.
+fn ignore_ascii_case(literal: &str) -> String {
+    literal
+        .chars()
+        .map(|c| match c.is_ascii_alphabetic() {
+            true => format!("[{}{}]", c.to_ascii_lowercase(), c.to_ascii_uppercase()),
+            false => c.to_string(),
+        })
+        .collect()
 }
 
 pub trait HttpCodeRanges {
@@ -167,20 +290,89 @@ pub struct Service {
     pub search_string: Option<String>,
     #[serde(default)]
     pub regexes: Vec<RegexSearch>,
+    /// Domains of original site, matched in addition to `regexes`.
+    #[serde(default)]
+    pub domains: Vec<DomainRule>,
+    /// Regexes mapping path on instance back to original URL.
+    #[serde(default)]
+    pub reverse_regexes: Vec<RegexSearch>,
     #[serde(default)]
     pub aliases: Vec<String>,
     #[serde(default)]
     pub source_link: Option<Url>,
     #[serde(default)]
     pub deprecated_message: Option<String>,
+    /// Name of service that replaces this deprecated service.
+    #[serde(default)]
+    pub replaced_by: Option<String>,
+    /// Regexes mapping path on this service to path on `replaced_by` service.
+    /// Path is kept as is if none match.
+    #[serde(default)]
+    pub replacement_regexes: Vec<RegexSearch>,
     pub instances: Vec<Instance>,
 }
 
+impl Service {
+    /// Regexes matching original URLs: `regexes` followed by compiled `domains`.
+    pub fn url_searches(&self) -> Vec<RegexSearch> {
+        let mut searches = self.regexes.clone();
+        searches.extend(self.domains.iter().flat_map(DomainRule::to_searches));
+        searches
+    }
+}
+
 pub type ServicesData = HashMap<String, Service>;
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GroupMember {
+    pub service: String,
+    /// Rewrites of original URL for this service. Service regexes are used if empty.
+    #[serde(default)]
+    pub regexes: Vec<RegexSearch>,
+    /// Use regexes of another service, for services sharing its URL layout.
+    #[serde(default)]
+    pub regexes_from: Option<String>,
+}
+
+impl GroupMember {
+    /// Regexes rewriting original URL to path on this service: own `regexes`,
+    /// then regexes of `regexes_from`, then regexes of the service itself.
+    ///
+    /// Returns `None` if referenced service does not exist.
+    pub fn url_searches(&self, services: &HashMap<&str, &Service>) -> Option<Vec<RegexSearch>> {
+        if !self.regexes.is_empty() {
+            return Some(self.regexes.clone());
+        }
+        let name = self.regexes_from.as_deref().unwrap_or(&self.service);
+        services.get(name).map(|service| service.url_searches())
+    }
+}
+
+/// Ordered set of services that can serve the same original URLs.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ServiceGroup {
+    pub name: String,
+    pub services: Vec<GroupMember>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct StoredData {
     pub services: Vec<Service>,
+    #[serde(default)]
+    pub groups: Vec<ServiceGroup>,
+}
+
+impl StoredData {
+    /// Service names, aliases and group names shadowed by redirect routes.
+    pub fn reserved_names(&self) -> Vec<&str> {
+        self.services
+            .iter()
+            .flat_map(|service| std::iter::once(&service.name).chain(&service.aliases))
+            .chain(self.groups.iter().map(|group| &group.name))
+            .map(String::as_str)
+            .filter(|name| is_reserved_name(name))
+            .collect()
+    }
 }
 
 pub struct ValidationResults {
@@ -247,11 +439,16 @@ impl StoredData {
     pub fn validate(&self) -> ValidationResults {
         let mut results = ValidationResults::new();
 
-        // Check if all regexes are valid
+        // Check if all regexes and URL templates are valid
         {
             for service in &self.services {
-                for regex in &service.regexes {
-                    regex::Regex::new(&regex.regex)
+                let regexes = service
+                    .regexes
+                    .iter()
+                    .chain(&service.reverse_regexes)
+                    .chain(&service.replacement_regexes);
+                for regex in regexes {
+                    let compiled = regex::Regex::new(&regex.regex)
                         .map_err(|e| {
                             results.add_error(format!(
                                 "Service {} has invalid regex {}: {}",
@@ -260,6 +457,238 @@ impl StoredData {
                             e
                         })
                         .ok();
+                    let template = UrlTemplate::parse(&regex.url)
+                        .map_err(|e| {
+                            results.add_error(format!(
+                                "Service {} has invalid URL template {}: {}",
+                                service.name, regex.url, e
+                            ));
+                            e
+                        })
+                        .ok();
+                    if let (Some(compiled), Some(template)) = (compiled, template)
+                        && let Err(e) = template.check_groups(&compiled)
+                    {
+                        results.add_error(format!(
+                            "Service {} URL template {} does not match regex {}: {}",
+                            service.name, regex.url, regex.regex, e
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Check if regex examples produce expected redirect paths
+        'examples: {
+            let matcher = match UrlMatcher::new(&self.services) {
+                Ok(matcher) => matcher,
+                Err(e) => {
+                    results.add_error(format!("Failed to build URL matcher: {}", e));
+                    break 'examples;
+                }
+            };
+
+            for service in &self.services {
+                for search in &service.url_searches() {
+                    for example in &search.examples {
+                        match matcher.rewrite(&example.input) {
+                            None => results.add_error(format!(
+                                "Service {} example {} is not matched by any regex",
+                                service.name, example.input
+                            )),
+                            Some(Err(e)) => results.add_error(format!(
+                                "Service {} example {} failed to render URL {}: {}",
+                                service.name, example.input, search.url, e
+                            )),
+                            Some(Ok((entry, _))) if entry.service_name != service.name => {
+                                results.add_error(format!(
+                                    "Service {} example {} is matched by service {}",
+                                    service.name, example.input, entry.service_name
+                                ))
+                            }
+                            Some(Ok((entry, url))) if entry.regex.as_str() != search.regex => {
+                                results.add_error(format!(
+                                    "Service {} example {} is matched by regex {} instead of {} (got {})",
+                                    service.name, example.input, entry.regex, search.regex, url
+                                ))
+                            }
+                            Some(Ok((_, url))) if url != example.expected => {
+                                results.add_error(format!(
+                                    "Service {} example {} produced {}, expected {}",
+                                    service.name, example.input, url, example.expected
+                                ))
+                            }
+                            Some(Ok(_)) => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        // Check if reverse regex examples produce expected original URLs and
+        // if forward and reverse templates round-trip
+        'round_trip: {
+            // Failure to build URL matcher is reported above.
+            let Ok(matcher) = UrlMatcher::new(&self.services) else {
+                break 'round_trip;
+            };
+            let reverse = match ReverseMatcher::new(&self.services) {
+                Ok(reverse) => reverse,
+                Err(e) => {
+                    results.add_error(format!("Failed to build reverse matcher: {}", e));
+                    break 'round_trip;
+                }
+            };
+
+            for service in &self.services {
+                if service.reverse_regexes.is_empty() {
+                    continue;
+                }
+
+                for search in &service.reverse_regexes {
+                    for example in &search.examples {
+                        match reverse.rewrite(&service.name, &example.input) {
+                            Some(Ok(url)) if url == example.expected => {}
+                            Some(Ok(url)) => results.add_error(format!(
+                                "Service {} reverse example {} produced {}, expected {}",
+                                service.name, example.input, url, example.expected
+                            )),
+                            Some(Err(e)) => results.add_error(format!(
+                                "Service {} reverse example {} failed to render URL: {}",
+                                service.name, example.input, e
+                            )),
+                            None => results.add_error(format!(
+                                "Service {} reverse example {} is not matched by any reverse regex",
+                                service.name, example.input
+                            )),
+                        }
+                    }
+                }
+
+                // forward(reverse(path)) must give the same path
+                let searches = service.url_searches();
+                let paths = searches
+                    .iter()
+                    .flat_map(|search| &search.examples)
+                    .map(|example| &example.expected);
+                for path in paths {
+                    let original = match reverse.rewrite(&service.name, path) {
+                        Some(Ok(original)) => original,
+                        _ => {
+                            results.add_error(format!(
+                                "Service {} path {} can not be mapped back to original URL",
+                                service.name, path
+                            ));
+                            continue;
+                        }
+                    };
+                    match matcher.rewrite(&original) {
+                        Some(Ok((entry, round_trip)))
+                            if entry.service_name == service.name && round_trip == *path => {}
+                        _ => results.add_error(format!(
+                            "Service {} path {} does not round-trip through original URL {}",
+                            service.name, path, original
+                        )),
+                    }
+                }
+
+                // reverse(forward(original)) must give the same original URL
+                let originals = service
+                    .reverse_regexes
+                    .iter()
+                    .flat_map(|search| &search.examples)
+                    .map(|example| &example.expected);
+                for original in originals {
+                    let round_trip = match matcher.rewrite(original) {
+                        Some(Ok((entry, path))) if entry.service_name == service.name => {
+                            reverse.rewrite(&service.name, &path)
+                        }
+                        _ => None,
+                    };
+                    if !matches!(round_trip, Some(Ok(ref url)) if url == original) {
+                        results.add_error(format!(
+                            "Service {} original URL {} does not round-trip through its regexes",
+                            service.name, original
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Check if regexes of different services can match the same URL
+        {
+            let searches: Vec<(&Service, Vec<RegexSearch>)> = self
+                .services
+                .iter()
+                .map(|service| (service, service.url_searches()))
+                .collect();
+            let compiled: Vec<(&Service, &RegexSearch, regex::Regex)> = searches
+                .iter()
+                .flat_map(|(service, searches)| {
+                    searches.iter().filter_map(move |search| {
+                        regex::Regex::new(&search.regex)
+                            .ok()
+                            .map(|regex| (*service, search, regex))
+                    })
+                })
+                .collect();
+            let mut reported: HashSet<(&str, &str)> = HashSet::new();
+
+            for (service, search, _) in &compiled {
+                let mut samples = regex_samples(&search.regex).unwrap_or_default();
+                samples.extend(search.examples.iter().map(|e| e.input.clone()));
+                for sample in &samples {
+                    for (other_service, other_search, other_regex) in &compiled {
+                        if service.name == other_service.name {
+                            continue;
+                        }
+                        let pair = if service.name < other_service.name {
+                            (service.name.as_str(), other_service.name.as_str())
+                        } else {
+                            (other_service.name.as_str(), service.name.as_str())
+                        };
+                        if reported.contains(&pair) || !other_regex.is_match(sample) {
+                            continue;
+                        }
+                        reported.insert(pair);
+                        let message = format!(
+                            "Services {} and {} have overlapping regexes {} and {} (sample URL {})",
+                            service.name,
+                            other_service.name,
+                            search.regex,
+                            other_search.regex,
+                            sample
+                        );
+                        if search.priority == other_search.priority {
+                            results.add_warning(format!("{message} with equal priority"));
+                        } else {
+                            results.add_notice(message);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Check if domains are valid
+        {
+            let domain_regex = regex::Regex::new(r"^(\*\.)?[a-z0-9-]+(\.[a-z0-9-]+)*$").unwrap();
+
+            for service in &self.services {
+                for rule in &service.domains {
+                    if !domain_regex.is_match(&rule.domain) {
+                        results.add_error(format!(
+                            "Service {} has invalid domain {}",
+                            service.name, rule.domain
+                        ));
+                    }
+                    for mapping in &rule.paths {
+                        if !mapping.from.starts_with('/') || !mapping.to.starts_with('/') {
+                            results.add_error(format!(
+                                "Service {} domain {} path mapping {} -> {} must use absolute paths",
+                                service.name, rule.domain, mapping.from, mapping.to
+                            ));
+                        }
+                    }
                 }
             }
         }
@@ -333,6 +762,13 @@ impl StoredData {
             }
         }
 
+        // Check if names, aliases and group names are not taken by routes
+        {
+            for name in self.reserved_names() {
+                results.add_error(format!("Name {name} is reserved by redirect routes"));
+            }
+        }
+
         // Check if all URLs have host
         {
             for service in &self.services {
@@ -356,6 +792,409 @@ impl StoredData {
             }
         }
 
+        // Check if instance weights are positive
+        {
+            for service in &self.services {
+                for instance in &service.instances {
+                    if instance.weight == Some(0) {
+                        results.add_error(format!(
+                            "Service {} instance {} has zero weight",
+                            service.name, instance.url
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Check if sub-path instance URLs end with slash
+        {
+            for service in &self.services {
+                for instance in &service.instances {
+                    if !instance.url.path().ends_with('/') {
+                        results.add_warning(format!(
+                            "Service {} has sub-path instance URL {} without trailing slash",
+                            service.name, instance.url
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Check if replacement chains resolve
+        'replacements: {
+            let services: HashMap<&str, &Service> = self
+                .services
+                .iter()
+                .map(|service| (service.name.as_str(), service))
+                .collect();
+            let replacements = match ReplacementMatcher::new(&self.services) {
+                Ok(replacements) => replacements,
+                Err(e) => {
+                    results.add_error(format!("Failed to build replacement matcher: {}", e));
+                    break 'replacements;
+                }
+            };
+
+            for service in &self.services {
+                let Some(replaced_by) = &service.replaced_by else {
+                    if !service.replacement_regexes.is_empty() {
+                        results.add_warning(format!(
+                            "Service {} has replacement regexes but no replacement",
+                            service.name
+                        ));
+                    }
+                    continue;
+                };
+                if service.deprecated_message.is_none() {
+                    results.add_warning(format!(
+                        "Service {} is replaced by {} but has no deprecation message",
+                        service.name, replaced_by
+                    ));
+                }
+
+                let mut chain = vec![service.name.as_str()];
+                let mut next = Some(replaced_by.as_str());
+                while let Some(name) = next {
+                    if chain.contains(&name) {
+                        chain.push(name);
+                        results.add_error(format!(
+                            "Service {} has replacement cycle {}",
+                            service.name,
+                            chain.join(" -> ")
+                        ));
+                        break;
+                    }
+                    chain.push(name);
+                    let Some(replacement) = services.get(name) else {
+                        results.add_error(format!(
+                            "Service {} replacement chain {} ends with unknown service",
+                            service.name,
+                            chain.join(" -> ")
+                        ));
+                        break;
+                    };
+                    next = replacement.replaced_by.as_deref();
+                    if next.is_none() && replacement.instances.is_empty() {
+                        results.add_warning(format!(
+                            "Service {} is replaced by {} which has no instances",
+                            service.name, name
+                        ));
+                    }
+                }
+
+                for example in service
+                    .replacement_regexes
+                    .iter()
+                    .flat_map(|search| &search.examples)
+                {
+                    match replacements.rewrite(&service.name, &example.input) {
+                        Some(Ok(url)) if url == example.expected => {}
+                        Some(Ok(url)) => results.add_error(format!(
+                            "Service {} replacement example {} produced {}, expected {}",
+                            service.name, example.input, url, example.expected
+                        )),
+                        Some(Err(e)) => results.add_error(format!(
+                            "Service {} replacement example {} failed to render URL: {}",
+                            service.name, example.input, e
+                        )),
+                        None => results.add_error(format!(
+                            "Service {} replacement example {} is not matched by any replacement regex",
+                            service.name, example.input
+                        )),
+                    }
+                }
+            }
+        }
+
+        // Check if service groups are valid
+        'groups: {
+            let name_regex = regex::Regex::new(r"^[a-z0-9-]+$").unwrap();
+            let services: HashMap<&str, &Service> = self
+                .services
+                .iter()
+                .map(|service| (service.name.as_str(), service))
+                .collect();
+            let groups = match GroupIndex::new(&self.groups, &self.services) {
+                Ok(groups) => groups,
+                Err(e) => {
+                    results.add_error(format!("Failed to build group matchers: {}", e));
+                    break 'groups;
+                }
+            };
+            let mut service_groups: HashMap<&str, &str> = HashMap::new();
+
+            for group in &self.groups {
+                if !name_regex.is_match(&group.name) {
+                    results.add_warning(format!("Group {} has invalid name format", group.name));
+                }
+                if services.contains_key(group.name.as_str())
+                    || self
+                        .services
+                        .iter()
+                        .any(|service| service.aliases.contains(&group.name))
+                {
+                    results.add_warning(format!(
+                        "Group {} name is shadowed by service with the same name or alias",
+                        group.name
+                    ));
+                }
+
+                for (i, member) in group.services.iter().enumerate() {
+                    if !services.contains_key(member.service.as_str()) {
+                        results.add_error(format!(
+                            "Group {} has unknown service {}",
+                            group.name, member.service
+                        ));
+                        continue;
+                    }
+                    if group.services[..i]
+                        .iter()
+                        .any(|other| other.service == member.service)
+                    {
+                        results.add_warning(format!(
+                            "Group {} has duplicate service {}",
+                            group.name, member.service
+                        ));
+                        continue;
+                    }
+                    match service_groups.get(member.service.as_str()) {
+                        Some(other) if *other != group.name => {
+                            results.add_warning(format!(
+                                "Service {} is in multiple groups {} and {}",
+                                member.service, other, group.name
+                            ));
+                        }
+                        _ => {
+                            service_groups.insert(&member.service, &group.name);
+                        }
+                    }
+
+                    match &member.regexes_from {
+                        Some(from) if !member.regexes.is_empty() => {
+                            results.add_error(format!(
+                                "Group {} service {} has both regexes and regexes_from {}",
+                                group.name, member.service, from
+                            ));
+                        }
+                        Some(from) if !services.contains_key(from.as_str()) => {
+                            results.add_error(format!(
+                                "Group {} service {} has regexes_from unknown service {}",
+                                group.name, member.service, from
+                            ));
+                        }
+                        _ => {}
+                    }
+
+                    for search in &member.regexes {
+                        let compiled = regex::Regex::new(&search.regex)
+                            .map_err(|e| {
+                                results.add_error(format!(
+                                    "Group {} service {} has invalid regex {}: {}",
+                                    group.name, member.service, search.regex, e
+                                ));
+                                e
+                            })
+                            .ok();
+                        let template = UrlTemplate::parse(&search.url)
+                            .map_err(|e| {
+                                results.add_error(format!(
+                                    "Group {} service {} has invalid URL template {}: {}",
+                                    group.name, member.service, search.url, e
+                                ));
+                                e
+                            })
+                            .ok();
+                        if let (Some(compiled), Some(template)) = (compiled, template)
+                            && let Err(e) = template.check_groups(&compiled)
+                        {
+                            results.add_error(format!(
+                                "Group {} service {} URL template {} does not match regex {}: {}",
+                                group.name, member.service, search.url, search.regex, e
+                            ));
+                        }
+                    }
+
+                    let (Some(compiled), Some(searches)) =
+                        (groups.get(&group.name), member.url_searches(&services))
+                    else {
+                        continue;
+                    };
+                    for example in searches.iter().flat_map(|search| &search.examples) {
+                        match compiled.rewrite(&member.service, &example.input) {
+                            Some(Ok(url)) if url == example.expected => {}
+                            Some(Ok(url)) => results.add_error(format!(
+                                "Group {} service {} example {} produced {}, expected {}",
+                                group.name, member.service, example.input, url, example.expected
+                            )),
+                            Some(Err(e)) => results.add_error(format!(
+                                "Group {} service {} example {} failed to render URL: {}",
+                                group.name, member.service, example.input, e
+                            )),
+                            None => results.add_error(format!(
+                                "Group {} service {} example {} is not matched by any regex",
+                                group.name, member.service, example.input
+                            )),
+                        }
+                    }
+                }
+            }
+        }
+
         results
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_regex_examples() {
+        let data: StoredData = serde_json::from_str(
+            r#"{"services": [{"type": "a", "instances": [], "deprecated_message": "x", "regexes": [
+                {"regex": "^example\\.com/(.*)", "url": "/$1", "examples": [
+                    {"input": "example.com/ok", "expected": "/ok"},
+                    {"input": "example.com/bad", "expected": "/good"},
+                    {"input": "example.org/", "expected": "/"}
+                ]}
+            ]}]}"#,
+        )
+        .unwrap();
+        let results = data.validate();
+        assert_eq!(results.errors.len(), 2, "{}", results.format());
+    }
+
+    #[test]
+    fn test_validate_reserved_names() {
+        let data: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "c", "instances": [], "deprecated_message": "x"},
+                {"type": "a", "aliases": ["~a", "cc"], "instances": [], "deprecated_message": "x"}
+            ], "groups": [{"name": "~g", "services": [{"service": "a"}]}]}"#,
+        )
+        .unwrap();
+        let results = data.validate();
+        assert_eq!(results.errors.len(), 3, "{}", results.format());
+    }
+
+    #[test]
+    fn test_validate_overlapping_regexes() {
+        let data: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": [], "deprecated_message": "x", "regexes": [
+                    {"regex": "^(www\\.)?(youtube|invidious\\.io)\\.com(/.*)", "url": "$3"}
+                ]},
+                {"type": "b", "instances": [], "deprecated_message": "x", "regexes": [
+                    {"regex": "^[a-z]+\\.io\\.com(/.*)", "url": "$1"}
+                ]}
+            ]}"#,
+        )
+        .unwrap();
+        let results = data.validate();
+        assert_eq!(results.warnings.len(), 1, "{}", results.format());
+        assert!(results.warnings[0].contains("Services a and b"));
+    }
+
+    #[test]
+    fn test_validate_groups() {
+        let data: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": [], "deprecated_message": "x"},
+                {"type": "b", "instances": [], "deprecated_message": "x"}
+            ], "groups": [
+                {"name": "example", "services": [
+                    {"service": "a"},
+                    {"service": "missing"},
+                    {"service": "b", "regexes": [{"regex": "^example\\.com(/.*)", "url": "/b$1", "examples": [
+                        {"input": "example.com/x", "expected": "/b/x"},
+                        {"input": "example.com/x", "expected": "/x"}
+                    ]}]}
+                ]},
+                {"name": "b", "services": [{"service": "a"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let results = data.validate();
+        assert_eq!(results.errors.len(), 2, "{}", results.format());
+        assert_eq!(results.warnings.len(), 2, "{}", results.format());
+    }
+
+    #[test]
+    fn test_validate_group_regexes_from() {
+        let data: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": [], "deprecated_message": "x", "regexes": [
+                    {"regex": "^example\\.com(/.*)", "url": "$1", "examples": [
+                        {"input": "example.com/x", "expected": "/x"}
+                    ]}
+                ]},
+                {"type": "b", "instances": [], "deprecated_message": "x"},
+                {"type": "d", "instances": [], "deprecated_message": "x"},
+                {"type": "e", "instances": [], "deprecated_message": "x"}
+            ], "groups": [
+                {"name": "example", "services": [
+                    {"service": "a"},
+                    {"service": "b", "regexes_from": "a"},
+                    {"service": "d", "regexes_from": "missing"},
+                    {"service": "e", "regexes_from": "a", "regexes": [{"regex": "^x(.*)", "url": "$1"}]}
+                ]}
+            ]}"#,
+        )
+        .unwrap();
+        let results = data.validate();
+        assert_eq!(results.errors.len(), 2, "{}", results.format());
+
+        let groups = GroupIndex::new(&data.groups, &data.services).unwrap();
+        let group = groups.get("example").unwrap();
+        assert_eq!(group.rewrite("b", "example.com/y").unwrap().unwrap(), "/y");
+        assert!(group.rewrite("d", "example.com/y").is_none());
+    }
+
+    #[test]
+    fn test_validate_replacements() {
+        let data: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": [], "deprecated_message": "x", "replaced_by": "b"},
+                {"type": "b", "instances": [], "deprecated_message": "x", "replaced_by": "a"},
+                {"type": "h", "instances": [], "deprecated_message": "x", "replaced_by": "missing"},
+                {"type": "d", "instances": [], "deprecated_message": "x", "replaced_by": "e",
+                 "replacement_regexes": [{"regex": "^/u/(.*)", "url": "/user/$1", "examples": [
+                    {"input": "/u/x", "expected": "/user/x"}
+                 ]}]},
+                {"type": "e", "instances": [{"url": "https://e.example.com/", "tags": []}]},
+                {"type": "f", "instances": [], "deprecated_message": "x", "replaced_by": "g"},
+                {"type": "g", "instances": []}
+            ]}"#,
+        )
+        .unwrap();
+        let results = data.validate();
+        assert_eq!(results.errors.len(), 3, "{}", results.format());
+        assert!(
+            results
+                .warnings
+                .contains(&"Service f is replaced by g which has no instances".to_string()),
+            "{}",
+            results.format()
+        );
+    }
+
+    #[test]
+    fn test_instance_weight() {
+        let data: StoredData = serde_json::from_str(
+            r#"{"services": [{"type": "a", "instances": [
+                {"url": "https://a.example.com/", "tags": []},
+                {"url": "https://b.example.com/", "tags": [], "weight": 4},
+                {"url": "https://c.example.com/", "tags": [], "weight": 0}
+            ]}]}"#,
+        )
+        .unwrap();
+        let instances = &data.services[0].instances;
+        assert_eq!(instances[0].weight(), 1);
+        assert_eq!(instances[1].weight(), 4);
+        // Default weight is not written back.
+        let json = serde_json::to_string(&instances[0]).unwrap();
+        assert!(!json.contains("weight"), "{json}");
+        let results = data.validate();
+        assert_eq!(results.errors.len(), 1, "{}", results.format());
+    }
+}
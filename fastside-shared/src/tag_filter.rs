@@ -0,0 +1,345 @@
+//! Boolean expressions over instance tags.
+//!
+//! Expression syntax:
+//!
+//! * `tag` - instance has the tag. Tags consist of letters, digits, `-`, `_`,
+//!   `.` and `:`.
+//! * `not x`, `!x` - negation.
+//! * `x and y`, `x & y` - conjunction.
+//! * `x or y`, `x | y` - disjunction.
+//! * `( ... )` - grouping.
+//!
+//! Keywords are case-insensitive. `not` binds tighter than `and`, which binds
+//! tighter than `or`, e.g. `ipv6 and (not cloudflare or https)`.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Maximum nesting of parentheses and negations.
+const MAX_DEPTH: usize = 32;
+/// Maximum number of tokens, bounding parse and match time.
+const MAX_TOKENS: usize = 1024;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TagFilterError {
+    #[error("empty tag filter")]
+    Empty,
+    #[error("unexpected character `{0}` at position {1}")]
+    UnexpectedChar(char, usize),
+    #[error("unexpected `{0}` at position {1}, expected {2}")]
+    UnexpectedToken(String, usize, &'static str),
+    #[error("unexpected end of tag filter, expected {0}")]
+    UnexpectedEnd(&'static str),
+    #[error("tag filter is nested too deeply")]
+    TooDeep,
+    #[error("tag filter is too long, maximum is {MAX_TOKENS} tokens")]
+    TooLong,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Tag(String),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Tag(tag) => write!(f, "{tag}"),
+            Token::Not => write!(f, "not"),
+            Token::And => write!(f, "and"),
+            Token::Or => write!(f, "or"),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+fn is_tag_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')
+}
+
+/// Split expression into tokens with their character positions.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, TagFilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '!' => Token::Not,
+            '&' => Token::And,
+            '|' => Token::Or,
+            c if is_tag_char(c) => {
+                let mut word = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| is_tag_char(*c)) {
+                    word.push(c);
+                }
+                match word.to_ascii_lowercase().as_str() {
+                    "not" => Token::Not,
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    _ => Token::Tag(word),
+                }
+            }
+            c => return Err(TagFilterError::UnexpectedChar(c, pos)),
+        };
+        if tokens.len() == MAX_TOKENS {
+            return Err(TagFilterError::TooLong);
+        }
+        tokens.push((token, pos));
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Tag(String),
+    Not(Box<Expr>),
+    /// Chains are kept flat, so tree depth is bounded by nesting.
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+impl Expr {
+    fn matches(&self, tags: &[String]) -> bool {
+        match self {
+            Expr::Tag(tag) => tags.contains(tag),
+            Expr::Not(expr) => !expr.matches(tags),
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.matches(tags)),
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.matches(tags)),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self, expected: &'static str) -> Result<(Token, usize), TagFilterError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or(TagFilterError::UnexpectedEnd(expected))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> Result<Expr, TagFilterError> {
+        let mut exprs = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            exprs.push(self.and()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.pop().unwrap(),
+            _ => Expr::Or(exprs),
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, TagFilterError> {
+        let mut exprs = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            exprs.push(self.unary()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.pop().unwrap(),
+            _ => Expr::And(exprs),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, TagFilterError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(TagFilterError::TooDeep);
+        }
+        let expected = "tag, `not` or `(`";
+        let expr = match self.next(expected)? {
+            (Token::Tag(tag), _) => Expr::Tag(tag),
+            (Token::Not, _) => Expr::Not(Box::new(self.unary()?)),
+            (Token::Open, _) => {
+                let expr = self.or()?;
+                match self.next("`)`")? {
+                    (Token::Close, _) => expr,
+                    (token, pos) => {
+                        return Err(TagFilterError::UnexpectedToken(
+                            token.to_string(),
+                            pos,
+                            "`)`",
+                        ));
+                    }
+                }
+            }
+            (token, pos) => {
+                return Err(TagFilterError::UnexpectedToken(
+                    token.to_string(),
+                    pos,
+                    expected,
+                ));
+            }
+        };
+        self.depth -= 1;
+        Ok(expr)
+    }
+}
+
+/// Parsed tag filter. Serialized as its source expression.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TagFilter {
+    source: String,
+    expr: Expr,
+}
+
+impl TagFilter {
+    pub fn parse(source: &str) -> Result<Self, TagFilterError> {
+        let tokens = tokenize(source)?;
+        if tokens.is_empty() {
+            return Err(TagFilterError::Empty);
+        }
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        if let Some((token, pos)) = parser.tokens.get(parser.position) {
+            return Err(TagFilterError::UnexpectedToken(
+                token.to_string(),
+                *pos,
+                "`and`, `or` or end",
+            ));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    /// Check if instance with `tags` matches the filter.
+    pub fn matches(&self, tags: &[String]) -> bool {
+        self.expr.matches(tags)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl TryFrom<String> for TagFilter {
+    type Error = TagFilterError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value)
+    }
+}
+
+impl From<TagFilter> for String {
+    fn from(value: TagFilter) -> Self {
+        value.source
+    }
+}
+
+impl fmt::Display for TagFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_matches() {
+        let filter = TagFilter::parse("tor OR i2p").unwrap();
+        assert!(filter.matches(&tags(&["tor"])));
+        assert!(filter.matches(&tags(&["i2p", "https"])));
+        assert!(!filter.matches(&tags(&["clearnet"])));
+
+        let filter = TagFilter::parse("ipv6 and (not cloudflare or https)").unwrap();
+        assert!(filter.matches(&tags(&["ipv6"])));
+        assert!(filter.matches(&tags(&["ipv6", "cloudflare", "https"])));
+        assert!(!filter.matches(&tags(&["ipv6", "cloudflare"])));
+        assert!(!filter.matches(&tags(&["https"])));
+
+        // `not` binds tighter than `and`, `and` tighter than `or`.
+        let filter = TagFilter::parse("!a & b | c").unwrap();
+        assert!(filter.matches(&tags(&["b"])));
+        assert!(filter.matches(&tags(&["a", "c"])));
+        assert!(!filter.matches(&tags(&["a", "b"])));
+
+        let filter = TagFilter::parse("country-de or country-nl").unwrap();
+        assert!(filter.matches(&tags(&["country-nl"])));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(TagFilter::parse("  "), Err(TagFilterError::Empty));
+        assert_eq!(
+            TagFilter::parse("tor or"),
+            Err(TagFilterError::UnexpectedEnd("tag, `not` or `(`"))
+        );
+        assert_eq!(
+            TagFilter::parse("(tor or i2p"),
+            Err(TagFilterError::UnexpectedEnd("`)`"))
+        );
+        assert_eq!(
+            TagFilter::parse("tor i2p"),
+            Err(TagFilterError::UnexpectedToken(
+                "i2p".to_string(),
+                4,
+                "`and`, `or` or end"
+            ))
+        );
+        assert_eq!(
+            TagFilter::parse("tor + i2p"),
+            Err(TagFilterError::UnexpectedChar('+', 4))
+        );
+        assert_eq!(
+            TagFilter::parse(&"(".repeat(100)),
+            Err(TagFilterError::TooDeep)
+        );
+        // Long flat chains used to build deep trees and overflow the stack.
+        assert_eq!(
+            TagFilter::parse(&vec!["a"; 300_000].join("|")),
+            Err(TagFilterError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let filter = TagFilter::parse(&vec!["a"; 500].join(" or ")).unwrap();
+        assert_eq!(filter.expr, Expr::Or(vec![Expr::Tag("a".to_string()); 500]));
+        assert!(filter.matches(&tags(&["a"])));
+        assert!(!filter.matches(&tags(&["b"])));
+    }
+
+    #[test]
+    fn test_serde() {
+        let filter: TagFilter = serde_json::from_str(r#""tor or i2p""#).unwrap();
+        assert_eq!(serde_json::to_string(&filter).unwrap(), r#""tor or i2p""#);
+        let err = serde_json::from_str::<TagFilter>(r#""tor or""#).unwrap_err();
+        assert!(err.to_string().contains("unexpected end"), "{err}");
+    }
+}
@@ -0,0 +1,699 @@
+//! URL templates used by regex redirects.
+//!
+//! Template syntax:
+//!
+//! * `$N` - N-th capture group.
+//! * `${name}`, `${N}` - named or numbered capture group.
+//! * `${query.param}` - decoded value of query parameter `param` of the input URL.
+//! * `${name:-default}` - use `default` if group did not participate in the match.
+//! * `${name:+template}` - render nested `template` only if group participated
+//!   in the match, or query parameter is present. Nothing is rendered otherwise.
+//! * `${name|lower|urlencode}` - apply filters from left to right. See [`Filter`].
+//! * `?` before `$` url-encodes the substituted value.
+//! * `\` escapes the next character.
+
+use std::{iter::Peekable, str::Chars, str::FromStr};
+
+use base64::prelude::*;
+use regex::{Captures, Regex};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReplaceArgsError {
+    #[error("invalid capture group number")]
+    InvalidCaptureGroup,
+    #[error("parse int error")]
+    ParseIntError(#[from] std::num::ParseIntError),
+    #[error("unterminated `${{` expression")]
+    UnterminatedExpression,
+    #[error("invalid expression `{0}`")]
+    InvalidExpression(String),
+    #[error("unknown filter `{0}`")]
+    UnknownFilter(String),
+    #[error("unknown capture group `{0}`")]
+    UnknownCaptureGroup(String),
+    #[error("no value for `{0}` and no default")]
+    MissingValue(String),
+    #[error("filter `{0}` failed: {1}")]
+    FilterFailed(&'static str, String),
+}
+
+/// Filter applied to substituted value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Lower,
+    Upper,
+    UrlEncode,
+    UrlDecode,
+    Base64Encode,
+    Base64Decode,
+    Base64UrlEncode,
+    Base64UrlDecode,
+}
+
+impl FromStr for Filter {
+    type Err = ReplaceArgsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim() {
+            "lower" => Self::Lower,
+            "upper" => Self::Upper,
+            "urlencode" => Self::UrlEncode,
+            "urldecode" => Self::UrlDecode,
+            "base64encode" => Self::Base64Encode,
+            "base64decode" => Self::Base64Decode,
+            "base64urlencode" => Self::Base64UrlEncode,
+            "base64urldecode" => Self::Base64UrlDecode,
+            other => return Err(ReplaceArgsError::UnknownFilter(other.to_string())),
+        })
+    }
+}
+
+impl Filter {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Lower => "lower",
+            Self::Upper => "upper",
+            Self::UrlEncode => "urlencode",
+            Self::UrlDecode => "urldecode",
+            Self::Base64Encode => "base64encode",
+            Self::Base64Decode => "base64decode",
+            Self::Base64UrlEncode => "base64urlencode",
+            Self::Base64UrlDecode => "base64urldecode",
+        }
+    }
+
+    fn apply(&self, value: String) -> Result<String, ReplaceArgsError> {
+        let failed =
+            |e: &dyn std::fmt::Display| ReplaceArgsError::FilterFailed(self.name(), e.to_string());
+        Ok(match self {
+            Self::Lower => value.to_lowercase(),
+            Self::Upper => value.to_uppercase(),
+            Self::UrlEncode => urlencoding::encode(&value).into_owned(),
+            Self::UrlDecode => urlencoding::decode(&value)
+                .map_err(|e| failed(&e))?
+                .into_owned(),
+            Self::Base64Encode => BASE64_STANDARD.encode(value.as_bytes()),
+            Self::Base64UrlEncode => BASE64_URL_SAFE_NO_PAD.encode(value.as_bytes()),
+            Self::Base64Decode | Self::Base64UrlDecode => {
+                let engine = if *self == Self::Base64Decode {
+                    &BASE64_STANDARD_NO_PAD
+                } else {
+                    &BASE64_URL_SAFE_NO_PAD
+                };
+                let decoded = engine
+                    .decode(value.trim_end_matches('=').as_bytes())
+                    .map_err(|e| failed(&e))?;
+                String::from_utf8(decoded).map_err(|e| failed(&e))?
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    Index(usize),
+    Name(String),
+    Query(String),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Query(param) => write!(f, "query.{param}"),
+        }
+    }
+}
+
+impl Source {
+    /// Get value of the source, or `None` if it did not match.
+    fn value(&self, input: &str, captures: &Captures) -> Option<String> {
+        match self {
+            Self::Index(index) => captures.get(*index).map(|m| m.as_str().to_string()),
+            Self::Name(name) => captures.name(name).map(|m| m.as_str().to_string()),
+            Self::Query(param) => get_query_param(input, param),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = ReplaceArgsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let is_name =
+            |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            Ok(Self::Index(s.parse()?))
+        } else if let Some(param) = s.strip_prefix("query.")
+            && !param.is_empty()
+        {
+            Ok(Self::Query(param.to_string()))
+        } else if is_name(s) {
+            Ok(Self::Name(s.to_string()))
+        } else {
+            Err(ReplaceArgsError::InvalidExpression(s.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Arg {
+        source: Source,
+        default: Option<String>,
+        filters: Vec<Filter>,
+    },
+    /// Nested template rendered only if source has a value.
+    Optional {
+        source: Source,
+        template: UrlTemplate,
+    },
+}
+
+/// Parsed URL template.
+#[derive(Debug, Clone)]
+pub struct UrlTemplate {
+    parts: Vec<Part>,
+}
+
+/// Read characters until one of `stops`, processing backslash escapes.
+///
+/// Returns read string and the stop character.
+fn read_until(
+    chars: &mut Peekable<Chars>,
+    stops: &[char],
+) -> Result<(String, char), ReplaceArgsError> {
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(
+                chars
+                    .next()
+                    .ok_or(ReplaceArgsError::UnterminatedExpression)?,
+            ),
+            c if stops.contains(&c) => return Ok((out, c)),
+            c => out.push(c),
+        }
+    }
+    Err(ReplaceArgsError::UnterminatedExpression)
+}
+
+/// Read nested template until closing brace, keeping escapes and nested
+/// `${...}` expressions as is.
+fn read_nested(chars: &mut Peekable<Chars>) -> Result<String, ReplaceArgsError> {
+    let mut out = String::new();
+    let mut depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                out.push(c);
+                out.push(
+                    chars
+                        .next()
+                        .ok_or(ReplaceArgsError::UnterminatedExpression)?,
+                );
+            }
+            '$' if chars.next_if_eq(&'{').is_some() => {
+                depth += 1;
+                out.push_str("${");
+            }
+            '}' if depth == 0 => return Ok(out),
+            '}' => {
+                depth -= 1;
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    Err(ReplaceArgsError::UnterminatedExpression)
+}
+
+/// Parse `${...}` expression. Opening brace must be already consumed.
+fn parse_expression(chars: &mut Peekable<Chars>) -> Result<Part, ReplaceArgsError> {
+    let (source, mut stop) = read_until(chars, &['}', ':', '|'])?;
+    let source = source.parse()?;
+    let mut default = None;
+    if stop == ':' {
+        match chars.next() {
+            Some('-') => {}
+            Some('+') => {
+                let template = UrlTemplate::parse(&read_nested(chars)?)?;
+                return Ok(Part::Optional { source, template });
+            }
+            _ => return Err(ReplaceArgsError::InvalidExpression(format!("{source}:"))),
+        }
+        let (value, next_stop) = read_until(chars, &['}', '|'])?;
+        default = Some(value);
+        stop = next_stop;
+    }
+    let mut filters = Vec::new();
+    while stop == '|' {
+        let (name, next_stop) = read_until(chars, &['}', '|'])?;
+        filters.push(name.parse()?);
+        stop = next_stop;
+    }
+    Ok(Part::Arg {
+        source,
+        default,
+        filters,
+    })
+}
+
+/// Parse argument after `$`. Returns `None` if `$` is not followed by an argument.
+fn parse_arg(chars: &mut Peekable<Chars>) -> Result<Option<Part>, ReplaceArgsError> {
+    match chars.peek() {
+        Some('{') => {
+            chars.next();
+            parse_expression(chars).map(Some)
+        }
+        Some('0'..='9') => {
+            let mut num = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                num.push(c);
+            }
+            Ok(Some(Part::Arg {
+                source: Source::Index(num.parse()?),
+                default: None,
+                filters: Vec::new(),
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+impl UrlTemplate {
+    pub fn parse(template: &str) -> Result<Self, ReplaceArgsError> {
+        let mut parts = Vec::new();
+        let mut literal = String::with_capacity(template.len());
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            let (is_encoded, prefix) = match c {
+                '\\' => {
+                    if let Some(c) = chars.next() {
+                        literal.push(c);
+                    }
+                    continue;
+                }
+                '?' if chars.peek() == Some(&'$') => {
+                    chars.next();
+                    (true, "?$")
+                }
+                '$' => (false, "$"),
+                c => {
+                    literal.push(c);
+                    continue;
+                }
+            };
+            match parse_arg(&mut chars)? {
+                Some(mut part) => {
+                    match &mut part {
+                        Part::Arg { filters, .. } if is_encoded => filters.push(Filter::UrlEncode),
+                        // Nested template decides what to encode.
+                        Part::Optional { source, .. } if is_encoded => {
+                            return Err(ReplaceArgsError::InvalidExpression(format!(
+                                "?${{{source}:+}}"
+                            )));
+                        }
+                        _ => {}
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                }
+                None => literal.push_str(prefix),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Check that every capture group referenced by template exists in `regex`.
+    pub fn check_groups(&self, regex: &Regex) -> Result<(), ReplaceArgsError> {
+        for part in &self.parts {
+            let source = match part {
+                Part::Literal(_) => continue,
+                Part::Arg { source, .. } => source,
+                Part::Optional { source, template } => {
+                    template.check_groups(regex)?;
+                    source
+                }
+            };
+            match source {
+                Source::Index(index) if *index >= regex.captures_len() => {
+                    return Err(ReplaceArgsError::InvalidCaptureGroup);
+                }
+                Source::Name(name) if !regex.capture_names().any(|n| n == Some(name.as_str())) => {
+                    return Err(ReplaceArgsError::UnknownCaptureGroup(name.clone()));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Render template for `input` matched with `captures`.
+    pub fn render(&self, input: &str, captures: &Captures) -> Result<String, ReplaceArgsError> {
+        let mut out = String::new();
+        for part in &self.parts {
+            let (source, default, filters) = match part {
+                Part::Literal(literal) => {
+                    out.push_str(literal);
+                    continue;
+                }
+                Part::Arg {
+                    source,
+                    default,
+                    filters,
+                } => (source, default, filters),
+                Part::Optional { source, template } => {
+                    if source.value(input, captures).is_some() {
+                        out.push_str(&template.render(input, captures)?);
+                    }
+                    continue;
+                }
+            };
+            let value = source.value(input, captures);
+            let mut value = match (value, default, source) {
+                (Some(value), _, _) => value,
+                (None, Some(default), _) => default.clone(),
+                (None, None, Source::Index(_)) => {
+                    return Err(ReplaceArgsError::InvalidCaptureGroup);
+                }
+                (None, None, source) => {
+                    return Err(ReplaceArgsError::MissingValue(source.to_string()));
+                }
+            };
+            for filter in filters {
+                value = filter.apply(value)?;
+            }
+            out.push_str(&value);
+        }
+        Ok(out)
+    }
+}
+
+impl UrlTemplate {
+    /// Convert template to `$N` replacement syntax used by JavaScript and
+    /// browser extensions, if possible.
+    ///
+    /// Only plain references to capture groups of `regex` are supported.
+    /// Default `/` of leading argument is dropped, because empty path after
+    /// instance URL is the root anyway. Returns `None` for query parameters,
+    /// filters, other defaults, optional templates and literal `$`.
+    pub fn to_numbered(&self, regex: &Regex) -> Option<String> {
+        let mut out = String::new();
+        let mut after_arg = false;
+        for (i, part) in self.parts.iter().enumerate() {
+            match part {
+                Part::Literal(literal) => {
+                    // `$1` followed by digit would read as another group.
+                    if literal.contains('$')
+                        || (after_arg && literal.starts_with(|c: char| c.is_ascii_digit()))
+                    {
+                        return None;
+                    }
+                    out.push_str(literal);
+                    after_arg = false;
+                }
+                Part::Arg {
+                    source,
+                    default,
+                    filters,
+                } => {
+                    if !filters.is_empty() || after_arg {
+                        return None;
+                    }
+                    match default.as_deref() {
+                        None => {}
+                        Some("/") if i == 0 => {}
+                        Some(_) => return None,
+                    }
+                    let index = match source {
+                        Source::Index(index) if *index < regex.captures_len() => *index,
+                        Source::Name(name) => regex
+                            .capture_names()
+                            .position(|n| n == Some(name.as_str()))?,
+                        _ => return None,
+                    };
+                    out.push_str(&format!("${index}"));
+                    after_arg = true;
+                }
+                Part::Optional { .. } => return None,
+            }
+        }
+        Some(out)
+    }
+}
+
+/// Get decoded value of query parameter `param` from `url`.
+fn get_query_param(url: &str, param: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    let query = query.split('#').next().unwrap_or_default();
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == param)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Substitute capture groups into URL template.
+///
+/// See module documentation for template syntax.
+pub fn replace_args_in_url(
+    url: &str,
+    input: &str,
+    captures: &Captures,
+) -> Result<String, ReplaceArgsError> {
+    UrlTemplate::parse(url)?.render(input, captures)
+}
+
+/// Escape literal text so it is rendered as is.
+pub fn escape(literal: &str) -> String {
+    let mut out = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '\\' | '$' | '?') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::{Captures, Regex};
+
+    fn setup_captures<'t>(text: &'t str, re: &'t str) -> Captures<'t> {
+        let regex = Regex::new(re).unwrap();
+        regex.captures(text).unwrap()
+    }
+
+    #[test]
+    fn test_no_placeholders() {
+        let url = "http://example.com/path";
+        let captures = setup_captures("input text", r"(input) (text)");
+        let result = replace_args_in_url(url, "", &captures);
+        assert_eq!(result.unwrap(), "http://example.com/path");
+    }
+
+    #[test]
+    fn test_simple_replacement() {
+        let url = "http://example.com/$1";
+        let captures = setup_captures("value", r"(value)");
+        let result = replace_args_in_url(url, "", &captures);
+        assert_eq!(result.unwrap(), "http://example.com/value");
+    }
+
+    #[test]
+    fn test_url_encoding() {
+        let url = r"http://example.com/\?param=?$1";
+        let captures = setup_captures("value space", r"(value space)");
+        let result = replace_args_in_url(url, "", &captures);
+        assert_eq!(result.unwrap(), "http://example.com/?param=value%20space");
+    }
+
+    #[test]
+    fn test_multiple_replacements() {
+        let url = r"http://example.com/$1/page\?$2";
+        let captures = setup_captures("value1 value2", r"(value1) (value2)");
+        let result = replace_args_in_url(url, "", &captures);
+        assert_eq!(result.unwrap(), "http://example.com/value1/page?value2");
+    }
+
+    #[test]
+    fn test_invalid_capture_group() {
+        let url = "http://example.com/$2";
+        let captures = setup_captures("value1", r"(value1)");
+        let result = replace_args_in_url(url, "", &captures);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_escape() {
+        let url = r"http://example.com/\$1";
+        let captures = setup_captures("value", r"(value)");
+        let result = replace_args_in_url(url, "", &captures);
+        assert_eq!(result.unwrap(), "http://example.com/$1");
+    }
+
+    #[test]
+    fn test_multiple_escape_characters() {
+        let url = r"http://example.com/\\\$1";
+        let captures = setup_captures("value", r"(value)");
+        let result = replace_args_in_url(url, "", &captures);
+        assert_eq!(result.unwrap(), r"http://example.com/\$1");
+    }
+
+    #[test]
+    fn test_named_group() {
+        let url = "/watch?v=${video}";
+        let captures = setup_captures("youtu.be/abc", r"youtu\.be/(?<video>\w+)");
+        let result = replace_args_in_url(url, "youtu.be/abc", &captures);
+        assert_eq!(result.unwrap(), "/watch?v=abc");
+    }
+
+    #[test]
+    fn test_default_value() {
+        let url = "/${1}/${2:-main}";
+        let captures = setup_captures("repo", r"(repo)(/\w+)?");
+        let result = replace_args_in_url(url, "repo", &captures);
+        assert_eq!(result.unwrap(), "/repo/main");
+    }
+
+    #[test]
+    fn test_missing_value_without_default() {
+        let url = "/${name}";
+        let captures = setup_captures("a", r"(?<name>b)?a");
+        let result = replace_args_in_url(url, "a", &captures);
+        assert!(matches!(result, Err(ReplaceArgsError::MissingValue(_))));
+    }
+
+    #[test]
+    fn test_query_param() {
+        let input = "youtu.be/abc?si=x&t=42";
+        let url = "/watch?v=${video}&t=${query.t:-0}";
+        let captures = setup_captures(input, r"youtu\.be/(?<video>\w+)");
+        let result = replace_args_in_url(url, input, &captures);
+        assert_eq!(result.unwrap(), "/watch?v=abc&t=42");
+
+        let input = "youtu.be/abc";
+        let captures = setup_captures(input, r"youtu\.be/(?<video>\w+)");
+        let result = replace_args_in_url(url, input, &captures);
+        assert_eq!(result.unwrap(), "/watch?v=abc&t=0");
+    }
+
+    #[test]
+    fn test_optional_template() {
+        let url = r"/watch?v=${video}${query.t:+&t=?${query.t}}${2:+/\}${2}}";
+        let regex = r"youtu\.be/(?<video>[a-w]+)(x)?";
+        let input = "youtu.be/abc?si=x&t=1 2";
+        let captures = setup_captures(input, regex);
+        let result = replace_args_in_url(url, input, &captures);
+        assert_eq!(result.unwrap(), "/watch?v=abc&t=1%202");
+
+        let input = "youtu.be/abcx?si=x";
+        let captures = setup_captures(input, regex);
+        let result = replace_args_in_url(url, input, &captures);
+        assert_eq!(result.unwrap(), "/watch?v=abc/}x");
+
+        assert!(matches!(
+            UrlTemplate::parse("/${1:+${2}"),
+            Err(ReplaceArgsError::UnterminatedExpression)
+        ));
+        assert!(matches!(
+            UrlTemplate::parse("/?${1:+x}"),
+            Err(ReplaceArgsError::InvalidExpression(_))
+        ));
+        let regex = Regex::new(r"(\w+)").unwrap();
+        assert!(
+            UrlTemplate::parse("/${1:+${2}}")
+                .unwrap()
+                .check_groups(&regex)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let url = "/${1|lower}/${2|urldecode}/${3|base64decode|upper}/?${4|base64urlencode}";
+        let input = "ABC a%20b aGVsbG8 hi?";
+        let captures = setup_captures(input, r"(\w+) (\S+) (\S+) (\S+)");
+        let result = replace_args_in_url(url, input, &captures);
+        assert_eq!(result.unwrap(), "/abc/a b/HELLO/aGk_");
+    }
+
+    #[test]
+    fn test_encoded_expression() {
+        let url = "/?q=?${1|lower}";
+        let captures = setup_captures("A B", r"(.+)");
+        let result = replace_args_in_url(url, "A B", &captures);
+        assert_eq!(result.unwrap(), "/?q=a%20b");
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        assert!(matches!(
+            UrlTemplate::parse("/${1"),
+            Err(ReplaceArgsError::UnterminatedExpression)
+        ));
+        assert!(matches!(
+            UrlTemplate::parse("/${1|nope}"),
+            Err(ReplaceArgsError::UnknownFilter(_))
+        ));
+        assert!(matches!(
+            UrlTemplate::parse("/${a b}"),
+            Err(ReplaceArgsError::InvalidExpression(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_groups() {
+        let regex = Regex::new(r"(?<video>\w+)").unwrap();
+        assert!(
+            UrlTemplate::parse("/${video}/$1")
+                .unwrap()
+                .check_groups(&regex)
+                .is_ok()
+        );
+        assert!(
+            UrlTemplate::parse("/$2")
+                .unwrap()
+                .check_groups(&regex)
+                .is_err()
+        );
+        assert!(
+            UrlTemplate::parse("/${id}")
+                .unwrap()
+                .check_groups(&regex)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_escape_literal() {
+        let literal = r"/a?b=$1\c";
+        let captures = setup_captures("x", r"(x)");
+        let result = replace_args_in_url(&escape(literal), "", &captures);
+        assert_eq!(result.unwrap(), literal);
+    }
+
+    #[test]
+    fn test_to_numbered() {
+        let regex = Regex::new(r"^(https?://)?example\.com(?<path>/.*)?$").unwrap();
+        let numbered = |template: &str| UrlTemplate::parse(template).unwrap().to_numbered(&regex);
+        assert_eq!(numbered("${path:-/}").as_deref(), Some("$2"));
+        assert_eq!(numbered("/r$2").as_deref(), Some("/r$2"));
+        assert_eq!(numbered("/r${path:-/}"), None);
+        assert_eq!(numbered("/?url=?$2"), None);
+        assert_eq!(numbered("${query.q}"), None);
+        assert_eq!(numbered("$2${1}"), None);
+        assert_eq!(numbered("$5"), None);
+        assert_eq!(numbered("${path:+$2}"), None);
+    }
+}
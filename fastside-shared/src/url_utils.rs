@@ -0,0 +1,88 @@
+//! URL helpers.
+
+use url::Url;
+
+/// Join path to instance URL keeping instance base path.
+///
+/// `Url::join` with absolute path drops base path, so `https://example.org/app/`
+/// joined with `/watch` gives `https://example.org/watch`. This function treats
+/// `path` as relative to the instance URL instead. Paths that look like
+/// absolute URLs are never resolved to another host, and `..` segments never
+/// climb above the instance base path.
+pub fn join_instance_url(instance: &Url, path: &str) -> Result<Url, url::ParseError> {
+    let mut base = instance.clone();
+    if !base.path().ends_with('/') {
+        let base_path = format!("{}/", base.path());
+        base.set_path(&base_path);
+    }
+    let path = remove_dot_segments(path);
+    base.join(&format!("./{}", path.trim_start_matches('/')))
+}
+
+/// Resolve `.` and `..` segments of path part, clamping at the root.
+///
+/// Segments are split on `\` too, and percent-encoded dots are recognized,
+/// the same way `Url::join` does it.
+fn remove_dot_segments(path: &str) -> String {
+    let (path, rest) = path.split_at(path.find(['?', '#']).unwrap_or(path.len()));
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split(['/', '\\']) {
+        match segment.to_ascii_lowercase().replace("%2e", ".").as_str() {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    format!("{}{}", segments.join("/"), rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_instance_url() {
+        let instance = Url::parse("https://example.org/invidious/").unwrap();
+        let url = join_instance_url(&instance, "/watch?v=x").unwrap();
+        assert_eq!(url.as_str(), "https://example.org/invidious/watch?v=x");
+
+        let instance = Url::parse("https://example.org/invidious").unwrap();
+        let url = join_instance_url(&instance, "/watch").unwrap();
+        assert_eq!(url.as_str(), "https://example.org/invidious/watch");
+
+        let instance = Url::parse("https://example.org").unwrap();
+        let url = join_instance_url(&instance, "").unwrap();
+        assert_eq!(url.as_str(), "https://example.org/");
+
+        let url = join_instance_url(&instance, "//evil.org/a").unwrap();
+        assert_eq!(url.host_str(), Some("example.org"));
+        let url = join_instance_url(&instance, "/https://evil.org/a").unwrap();
+        assert_eq!(url.host_str(), Some("example.org"));
+    }
+
+    #[test]
+    fn test_join_instance_url_dot_segments() {
+        let instance = Url::parse("https://example.org/invidious/").unwrap();
+        for path in [
+            "../admin",
+            "/../admin",
+            "/a/../../admin",
+            "/%2e%2e/admin",
+            "/.%2E/admin",
+            "/..\\admin",
+            "/./../admin",
+        ] {
+            let url = join_instance_url(&instance, path).unwrap();
+            assert_eq!(
+                url.as_str(),
+                "https://example.org/invidious/admin",
+                "{path}"
+            );
+        }
+
+        let url = join_instance_url(&instance, "/a/./b/../c?q=../x#..").unwrap();
+        assert_eq!(url.as_str(), "https://example.org/invidious/a/c?q=../x#..");
+    }
+}
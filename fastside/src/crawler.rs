@@ -1,23 +1,30 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::{
-    sync::{Mutex, MutexGuard, RwLock},
+    sync::{Mutex, MutexGuard, RwLock, Semaphore},
     time::sleep,
 };
 use url::Url;
 
-use crate::{config::CrawlerConfig, types::LoadedData};
+use crate::{
+    config::CrawlerConfig,
+    geoip::{GeoIp, GeoLocation},
+    types::{LoadedData, LoadedDataStore},
+};
 use fastside_shared::{
-    client_builder::build_client,
+    client_builder::{build_client, client_builder},
+    config::{ProxyData, UserConfig},
     parallel::Parallelise,
     serde_types::{HttpCodeRanges, Instance, Service},
+    url_utils::join_instance_url,
 };
 
 #[derive(Error, Debug)]
@@ -41,6 +48,8 @@ pub enum CrawledInstanceStatus {
     BodyError,
     DecodeError,
     TimedOut,
+    /// Custom instance resolves to address that is not allowed.
+    ForbiddenAddress,
     Unknown,
 }
 
@@ -52,6 +61,32 @@ impl CrawledInstanceStatus {
             _ => isize::MAX,
         }
     }
+
+    /// Response time of the last crawl, if instance responded.
+    pub fn latency(&self) -> Option<Duration> {
+        match self {
+            Self::Ok(d) | Self::InvalidStatusCode(_, d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    /// Reason why the last crawl failed, `None` if instance is alive.
+    pub fn failure_reason(&self) -> Option<String> {
+        Some(match self {
+            Self::Ok(_) => return None,
+            Self::InvalidStatusCode(code, _) => format!("unexpected status code {code}"),
+            Self::StringNotFound => "search string not found".to_string(),
+            Self::ConnectionError => "connection failed".to_string(),
+            Self::RedirectPolicyError => "redirect policy violated".to_string(),
+            Self::BuilderError => "failed to build request".to_string(),
+            Self::RequestError => "request failed".to_string(),
+            Self::BodyError => "failed to read body".to_string(),
+            Self::DecodeError => "failed to decode body".to_string(),
+            Self::TimedOut => "timed out".to_string(),
+            Self::ForbiddenAddress => "address is not allowed".to_string(),
+            Self::Unknown => "unknown error".to_string(),
+        })
+    }
 }
 
 impl std::fmt::Display for CrawledInstanceStatus {
@@ -65,6 +100,44 @@ pub struct CrawledInstance {
     pub url: Url,
     pub status: CrawledInstanceStatus,
     pub tags: Vec<String>,
+    /// Results of last crawls, oldest first. `true` means instance was alive.
+    #[serde(default)]
+    pub history: VecDeque<bool>,
+    /// Location resolved from GeoIP database.
+    #[serde(default)]
+    pub location: Option<GeoLocation>,
+    /// Relative capacity from services file.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+const fn default_weight() -> u32 {
+    1
+}
+
+impl CrawledInstance {
+    /// Percentage of successful crawls in history.
+    ///
+    /// Falls back to current status if history is empty.
+    pub fn uptime(&self) -> f64 {
+        if self.history.is_empty() {
+            return match self.status {
+                CrawledInstanceStatus::Ok(_) => 100.0,
+                _ => 0.0,
+            };
+        }
+        let alive = self.history.iter().filter(|alive| **alive).count();
+        alive as f64 * 100.0 / self.history.len() as f64
+    }
+
+    /// Append current status to history inherited from the previous crawl.
+    fn push_history(&mut self, mut previous: VecDeque<bool>, size: usize) {
+        previous.push_back(matches!(self.status, CrawledInstanceStatus::Ok(_)));
+        while previous.len() > size {
+            previous.pop_front();
+        }
+        self.history = previous;
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -133,21 +206,82 @@ impl AsRef<CrawledData> for CrawledData {
     }
 }
 
+/// Alive user-supplied instances by service name.
+pub type CustomInstances = HashMap<String, Vec<CrawledInstance>>;
+
+/// Check if address is publicly routable.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network", 0.0.0.0/8
+                || a == 0
+                // Shared address space, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking, 198.18.0.0/15
+                || (a == 198 && (b & 0xfe) == 18)
+                // Reserved and broadcast, 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            // NAT64, 64:ff9b::/96, reaches embedded IPv4 address.
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., a, b, c, d] = ip.octets();
+                return is_public_ip(IpAddr::from([a, b, c, d]));
+            }
+            let first = segments[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80
+                // Documentation, 2001:db8::/32
+                || (first == 0x2001 && segments[1] == 0xdb8))
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Crawler {
-    loaded_data: Arc<RwLock<LoadedData>>,
+    loaded_data: Arc<LoadedDataStore>,
     config: Arc<CrawlerConfig>,
+    geoip: Option<Arc<GeoIp>>,
     data: RwLock<CrawledData>,
     crawler_lock: Mutex<()>,
+    /// Checked custom instances by service name and URL, with check time.
+    custom_cache: std::sync::Mutex<HashMap<(String, Url), (Instant, CrawledInstance)>>,
+    /// Limits custom instance checks running at once.
+    custom_checks: Semaphore,
 }
 
 impl Crawler {
-    pub fn new(loaded_data: Arc<RwLock<LoadedData>>, config: CrawlerConfig) -> Self {
+    pub fn new(
+        loaded_data: Arc<LoadedDataStore>,
+        config: CrawlerConfig,
+        geoip: Option<Arc<GeoIp>>,
+    ) -> Self {
         Self {
             loaded_data,
+            custom_checks: Semaphore::new(config.custom_instances.max_concurrent_checks),
             config: Arc::new(config),
+            geoip,
             data: RwLock::new(CrawledData::InitialLoading),
             crawler_lock: Mutex::new(()),
+            custom_cache: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
@@ -185,7 +319,7 @@ impl Crawler {
 
     /// Initialize crawler with default data from services.json without pinging
     pub async fn initialize_with_defaults(&self) {
-        let loaded_data = self.loaded_data.read().await;
+        let loaded_data = self.loaded_data.snapshot().await;
         let mut crawled_services: HashMap<String, CrawledService> = HashMap::new();
 
         for (name, service) in &loaded_data.services {
@@ -195,6 +329,9 @@ impl Crawler {
                     url: instance.url.clone(),
                     status: CrawledInstanceStatus::Ok(Duration::from_millis(0)),
                     tags: instance.tags.clone(),
+                    history: VecDeque::new(),
+                    location: None,
+                    weight: instance.weight(),
                 });
             }
             crawled_services.insert(
@@ -219,20 +356,24 @@ impl Crawler {
         self.data.read().await
     }
 
-    async fn crawl_single_instance(
-        config: Arc<CrawlerConfig>,
-        loaded_data: Arc<RwLock<LoadedData>>,
-        service: Arc<Service>,
-        instance: Instance,
-    ) -> Result<(CrawledInstance, String), CrawlerError> {
-        let client = build_client(
-            service.as_ref(),
-            config.as_ref(),
-            &loaded_data.read().await.proxies,
-            &instance,
-        )?;
-
-        let test_url = instance.url.join(&service.test_url)?;
+    /// Request test URL of instance and check response.
+    async fn check_instance(
+        config: &CrawlerConfig,
+        proxies: &ProxyData,
+        service: &Service,
+        instance: &Instance,
+    ) -> Result<CrawledInstanceStatus, CrawlerError> {
+        let client = build_client(service, config, proxies, instance)?;
+        Self::request_instance(&client, service, instance).await
+    }
+
+    /// Request test URL of instance with `client` and check response.
+    async fn request_instance(
+        client: &reqwest::Client,
+        service: &Service,
+        instance: &Instance,
+    ) -> Result<CrawledInstanceStatus, CrawlerError> {
+        let test_url = join_instance_url(&instance.url, &service.test_url)?;
         let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         let response = client.get(test_url).send().await;
         let status = match response {
@@ -268,12 +409,38 @@ impl Crawler {
                 _ => CrawledInstanceStatus::Unknown,
             },
         };
+        Ok(status)
+    }
+
+    async fn crawl_single_instance(
+        config: Arc<CrawlerConfig>,
+        geoip: Option<Arc<GeoIp>>,
+        loaded_data: Arc<LoadedData>,
+        service: Arc<Service>,
+        instance: Instance,
+    ) -> Result<(CrawledInstance, String), CrawlerError> {
+        let status =
+            Self::check_instance(&config, &loaded_data.proxies, &service, &instance).await?;
+
+        let location = match &geoip {
+            Some(geoip) => geoip.lookup_url(&instance.url).await,
+            None => None,
+        };
+        let mut tags = instance.tags.clone();
+        for tag in location.iter().flat_map(GeoLocation::tags) {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
 
         let ret = (
             CrawledInstance {
                 url: instance.url.clone(),
-                tags: instance.tags.clone(),
+                tags,
                 status,
+                history: VecDeque::new(),
+                location,
+                weight: instance.weight(),
             },
             service.name.clone(),
         );
@@ -281,6 +448,142 @@ impl Crawler {
         Ok(ret)
     }
 
+    /// Check custom instance with client connecting only to `addrs`, which
+    /// were checked to be public. Redirects are followed within the same host.
+    async fn check_pinned_instance(
+        &self,
+        loaded_data: &LoadedData,
+        service: &Service,
+        instance: &Instance,
+        addrs: &[SocketAddr],
+    ) -> Result<CrawledInstanceStatus, CrawlerError> {
+        let mut builder = client_builder(service, &self.config, &loaded_data.proxies, instance)?;
+        if let Some(host) = instance.url.host_str() {
+            builder = builder.resolve_to_addrs(host, addrs);
+            if service.follow_redirects {
+                let host = host.to_string();
+                builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                    if attempt.previous().len() >= 10 {
+                        attempt.error("too many redirects")
+                    } else if attempt.url().host_str() == Some(&host) {
+                        attempt.follow()
+                    } else {
+                        attempt.stop()
+                    }
+                }));
+            }
+        }
+        Self::request_instance(&builder.build()?, service, instance).await
+    }
+
+    /// Check custom instance of the service, or reuse recent result.
+    ///
+    /// Returns `None` if too many checks are running.
+    async fn check_custom_instance(
+        &self,
+        loaded_data: &LoadedData,
+        service: &Service,
+        url: &Url,
+    ) -> Option<CrawledInstance> {
+        let custom_config = &self.config.custom_instances;
+        let key = (service.name.clone(), url.clone());
+        if let Some((checked, instance)) = self.custom_cache.lock().unwrap().get(&key)
+            && checked.elapsed() < custom_config.cache_ttl
+        {
+            return Some(instance.clone());
+        }
+        let Ok(_permit) = self.custom_checks.try_acquire() else {
+            debug!("Too many custom instance checks, skipping {url}");
+            return None;
+        };
+
+        let instance = Instance::from(url.clone());
+        let status = if custom_config.allow_private {
+            Self::check_instance(&self.config, &loaded_data.proxies, service, &instance).await
+        } else {
+            let addrs: Vec<SocketAddr> = match (url.host_str(), url.port_or_known_default()) {
+                (Some(host), Some(port)) => tokio::net::lookup_host((host, port))
+                    .await
+                    .map(Iterator::collect)
+                    .unwrap_or_default(),
+                _ => Vec::new(),
+            };
+            if !addrs.is_empty() && addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+                self.check_pinned_instance(loaded_data, service, &instance, &addrs)
+                    .await
+            } else {
+                Ok(CrawledInstanceStatus::ForbiddenAddress)
+            }
+        }
+        .unwrap_or(CrawledInstanceStatus::Unknown);
+        debug!(
+            "Checked custom instance {url} of {}: {status}",
+            service.name
+        );
+
+        let location = match (&self.geoip, &status) {
+            (_, CrawledInstanceStatus::ForbiddenAddress) | (None, _) => None,
+            (Some(geoip), _) => geoip.lookup_url(url).await,
+        };
+        let checked = CrawledInstance {
+            url: url.clone(),
+            status,
+            tags: location.iter().flat_map(GeoLocation::tags).collect(),
+            history: VecDeque::new(),
+            location,
+            weight: instance.weight(),
+        };
+
+        let mut cache = self.custom_cache.lock().unwrap();
+        cache.retain(|_, (checked, _)| checked.elapsed() < custom_config.cache_ttl);
+        cache.insert(key, (Instant::now(), checked.clone()));
+        Some(checked)
+    }
+
+    /// Check custom instances from user config for `services`.
+    ///
+    /// Returns alive instances by service name. Only `http` and `https` URLs
+    /// are checked, at most `custom_instances.max_instances` of them.
+    pub async fn check_custom_instances(
+        &self,
+        loaded_data: &LoadedData,
+        user_config: &UserConfig,
+        services: &[&str],
+    ) -> CustomInstances {
+        let mut custom = CustomInstances::new();
+        if !self.config.custom_instances.enabled || user_config.custom_instances.is_empty() {
+            return custom;
+        }
+        let to_check: Vec<(&Service, &Url)> = services
+            .iter()
+            .filter_map(|name| loaded_data.services.get(*name))
+            .flat_map(|service| {
+                user_config
+                    .custom_instances_for(service)
+                    .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+                    .map(move |url| (service, url))
+            })
+            .take(self.config.custom_instances.max_instances)
+            .collect();
+        let checked = futures::future::join_all(
+            to_check
+                .iter()
+                .map(|(service, url)| self.check_custom_instance(loaded_data, service, url)),
+        )
+        .await;
+        for ((service, _), instance) in to_check.iter().zip(checked) {
+            if let Some(instance) = instance
+                && matches!(instance.status, CrawledInstanceStatus::Ok(_))
+            {
+                custom
+                    .entry(service.name.clone())
+                    .or_default()
+                    .push(instance);
+            }
+        }
+        custom
+    }
+
     async fn crawl<'a>(
         &self,
         crawler_guard: Option<MutexGuard<'a, ()>>,
@@ -297,10 +600,9 @@ impl Crawler {
             }
         };
 
-        let mut crawled_services: HashMap<String, CrawledService> = self
-            .loaded_data
-            .read()
-            .await
+        // Use the same snapshot for the whole crawl, even if services are reloaded meanwhile.
+        let loaded_data = self.loaded_data.snapshot().await;
+        let mut crawled_services: HashMap<String, CrawledService> = loaded_data
             .services
             .keys()
             .map(|name| {
@@ -315,16 +617,18 @@ impl Crawler {
             .collect();
         let mut parallelise = Parallelise::with_capacity(self.config.max_concurrent_requests);
 
-        for service in self.loaded_data.read().await.services.values() {
+        for service in loaded_data.services.values() {
             let service = Arc::new(service.clone());
             for instance in &service.instances {
-                let loaded_data = self.loaded_data.clone();
+                let loaded_data = loaded_data.clone();
                 let config = self.config.clone();
+                let geoip = self.geoip.clone();
                 let instance = instance.clone();
                 parallelise
                     .push(tokio::spawn(Self::crawl_single_instance(
                         config,
-                        loaded_data.clone(),
+                        geoip,
+                        loaded_data,
                         service.clone(),
                         instance,
                     )))
@@ -350,6 +654,21 @@ impl Crawler {
         }
 
         let mut data = self.data.write().await;
+
+        // Carry over history of previous crawl. Data initialized from
+        // services.json has empty history, data loaded from file keeps it.
+        let previous = data.get_services();
+        for service in crawled_services.values_mut() {
+            let previous_service = previous.and_then(|p| p.services.get(&service.name));
+            for instance in &mut service.instances {
+                let history = previous_service
+                    .and_then(|s| s.instances.iter().find(|i| i.url == instance.url))
+                    .map(|i| i.history.clone())
+                    .unwrap_or_default();
+                instance.push_history(history, self.config.history_size);
+            }
+        }
+
         data.replace(CrawledData::CrawledServices(CrawledServices {
             services: crawled_services,
             time: Utc::now(),
@@ -405,3 +724,61 @@ impl Crawler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(status: CrawledInstanceStatus) -> CrawledInstance {
+        CrawledInstance {
+            url: Url::parse("https://example.com/").unwrap(),
+            status,
+            tags: vec![],
+            history: VecDeque::new(),
+            location: None,
+            weight: 1,
+        }
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in ["1.1.1.1", "2606:4700::1111", "64:ff9b::101:101"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "0.1.2.3",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "198.18.0.1",
+            "192.0.0.8",
+            "ff02::1",
+            "2001:db8::1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_uptime_history() {
+        let mut current = instance(CrawledInstanceStatus::TimedOut);
+        assert_eq!(current.uptime(), 0.0);
+        current.push_history(VecDeque::from([true, true, false, true]), 4);
+        assert_eq!(current.history, VecDeque::from([true, false, true, false]));
+        assert_eq!(current.uptime(), 50.0);
+
+        let current = instance(CrawledInstanceStatus::Ok(Duration::from_millis(10)));
+        assert_eq!(current.uptime(), 100.0);
+    }
+}
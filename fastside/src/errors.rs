@@ -57,7 +57,7 @@ macro_rules! impl_api_error {
     };
 }
 
-use crate::search::SearchError;
+use crate::{search::SearchError, short_codes::ShortCodeError};
 
 #[derive(Error, Debug)]
 pub enum RedirectError {
@@ -67,16 +67,30 @@ pub enum RedirectError {
     UrlParse(#[from] url::ParseError),
     #[error("user config error: `{0}`")]
     UserConfig(#[from] fastside_shared::errors::UserConfigError),
+    #[error("short code error: `{0}`")]
+    ShortCode(#[from] ShortCodeError),
+    #[error("request from another site was rejected")]
+    CrossOrigin,
 }
 
 impl_template_error!(RedirectError,
     status => {
         RedirectError::Search(s) => match s {
             SearchError::ServiceNotFound => StatusCode::NOT_FOUND,
+            SearchError::OriginalNotFound => StatusCode::NOT_FOUND,
             _ => StatusCode::INTERNAL_SERVER_ERROR
         },
         RedirectError::UrlParse(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        RedirectError::UserConfig(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        RedirectError::UserConfig(_) => StatusCode::BAD_REQUEST,
+        RedirectError::ShortCode(s) => match s {
+            ShortCodeError::Disabled | ShortCodeError::NotFound => StatusCode::NOT_FOUND,
+            ShortCodeError::TooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            ShortCodeError::Full => StatusCode::INSUFFICIENT_STORAGE,
+            ShortCodeError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ShortCodeError::UserConfig(_) => StatusCode::BAD_REQUEST,
+            ShortCodeError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        RedirectError::CrossOrigin => StatusCode::FORBIDDEN,
     }
 );
 
@@ -0,0 +1,386 @@
+//! Export of services and healthy instances as rules for browser extensions,
+//! so browsers can redirect locally without a round trip to Fastside.
+//!
+//! Rule based formats redirect to the fastest instance of each service at the
+//! time of export. Only regexes and URL templates expressible in JavaScript
+//! `$N` syntax are exported, see [`UrlTemplate::to_numbered`]. Service groups
+//! are not exported.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use fastside_shared::{
+    config::UserConfig,
+    serde_types::{Service, ServicesData},
+    url_template::UrlTemplate,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    VERSION,
+    crawler::{CrawledInstance, CrawledServices},
+    import::LIBREDIRECT_NAMES,
+    search::get_redirect_instances,
+};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// LibRedirect `data.json` with instances per frontend and network.
+    Libredirect,
+    /// Redirector extension import file.
+    Redirector,
+    /// AdGuard filter list with `$urltransform` rules.
+    Adguard,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Libredirect | Self::Redirector => "application/json",
+            Self::Adguard => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+/// Instances of the service matching user config.
+fn service_instances<'a>(
+    crawled: &'a CrawledServices,
+    service: &Service,
+    user_config: &UserConfig,
+) -> Vec<&'a CrawledInstance> {
+    crawled
+        .services
+        .get(&service.name)
+        .and_then(|crawled| get_redirect_instances(crawled, &user_config.for_service(service), &[]))
+        .unwrap_or_default()
+}
+
+/// Services sorted by name, so exports are stable.
+fn sorted_services(services: &ServicesData) -> Vec<&Service> {
+    let mut services: Vec<&Service> = services.values().collect();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    services
+}
+
+/// Convert regex to JavaScript syntax. Returns `None` for inline flags and
+/// other syntax JavaScript lacks.
+fn js_regex(regex: &str) -> Option<String> {
+    let regex = regex.replace("(?P<", "(?<");
+    let has_flags = regex.match_indices("(?").any(|(i, _)| {
+        !regex[..i].ends_with('\\') && !matches!(regex[i + 2..].chars().next(), Some('<' | ':'))
+    });
+    if has_flags || regex.contains("\\A") || regex.contains("\\z") || regex.contains("[[:") {
+        return None;
+    }
+    Some(regex)
+}
+
+/// Regex redirect to fixed instance.
+#[derive(Debug)]
+struct Rule<'a> {
+    service: &'a str,
+    regex: String,
+    /// Instance URL followed by path in `$N` syntax.
+    replacement: String,
+    /// Example input and its redirect.
+    example: Option<(String, String)>,
+    priority: i32,
+}
+
+fn rules<'a>(
+    services: &'a ServicesData,
+    crawled: &CrawledServices,
+    user_config: &UserConfig,
+) -> Vec<Rule<'a>> {
+    let mut rules = Vec::new();
+    for service in sorted_services(services) {
+        let Some(instance) = service_instances(crawled, service, user_config)
+            .into_iter()
+            .min_by_key(|i| i.status.as_isize())
+        else {
+            continue;
+        };
+        let base = instance.url.as_str().trim_end_matches('/');
+        for search in service.url_searches() {
+            let (Ok(regex), Ok(template)) =
+                (Regex::new(&search.regex), UrlTemplate::parse(&search.url))
+            else {
+                continue;
+            };
+            let (Some(js), Some(path)) = (js_regex(&search.regex), template.to_numbered(&regex))
+            else {
+                debug!("Not exporting regex {} of {}", search.regex, service.name);
+                continue;
+            };
+            let example = search.examples.first().and_then(|example| {
+                let captures = regex.captures(&example.input)?;
+                let path = template.render(&example.input, &captures).ok()?;
+                Some((example.input.clone(), format!("{base}{path}")))
+            });
+            rules.push(Rule {
+                service: &service.name,
+                regex: js,
+                replacement: format!("{base}{path}"),
+                example,
+                priority: search.priority,
+            });
+        }
+    }
+    // Same order as URL matcher: higher priority first, then by service name.
+    rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+    rules
+}
+
+/// Instance URLs per LibRedirect frontend name and network.
+fn export_libredirect(
+    services: &ServicesData,
+    crawled: &CrawledServices,
+    user_config: &UserConfig,
+) -> String {
+    let mut data: BTreeMap<&str, BTreeMap<&str, Vec<&str>>> = BTreeMap::new();
+    for service in sorted_services(services) {
+        let name = LIBREDIRECT_NAMES
+            .iter()
+            .find(|(_, fastside)| *fastside == service.name)
+            .map_or(service.name.as_str(), |(libredirect, _)| libredirect);
+        let mut networks: BTreeMap<&str, Vec<&str>> = ["clearnet", "tor", "i2p", "loki"]
+            .into_iter()
+            .map(|network| (network, Vec::new()))
+            .collect();
+        for instance in service_instances(crawled, service, user_config) {
+            let network = ["tor", "i2p"]
+                .into_iter()
+                .find(|network| instance.tags.iter().any(|tag| tag == network))
+                .unwrap_or("clearnet");
+            networks
+                .entry(network)
+                .or_default()
+                .push(instance.url.as_str().trim_end_matches('/'));
+        }
+        data.insert(name, networks);
+    }
+    serde_json::to_string_pretty(&data).expect("failed to serialize export")
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RedirectorRedirect<'a> {
+    description: String,
+    example_url: String,
+    example_result: String,
+    error: Option<String>,
+    include_pattern: &'a str,
+    exclude_pattern: &'a str,
+    pattern_desc: &'a str,
+    redirect_url: &'a str,
+    pattern_type: &'a str,
+    process_matches: &'a str,
+    disabled: bool,
+    grouped: bool,
+    applies_to: [&'a str; 1],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RedirectorExport<'a> {
+    created_by: String,
+    created_at: String,
+    redirects: Vec<RedirectorRedirect<'a>>,
+}
+
+fn export_redirector(
+    services: &ServicesData,
+    crawled: &CrawledServices,
+    user_config: &UserConfig,
+) -> String {
+    let rules = rules(services, crawled, user_config);
+    let redirects = rules
+        .iter()
+        .map(|rule| {
+            let (example_url, example_result) = rule.example.clone().unzip();
+            RedirectorRedirect {
+                description: format!("Fastside: {}", rule.service),
+                example_url: example_url.unwrap_or_default(),
+                example_result: example_result.unwrap_or_default(),
+                error: None,
+                include_pattern: &rule.regex,
+                exclude_pattern: "",
+                pattern_desc: "",
+                redirect_url: &rule.replacement,
+                pattern_type: "R",
+                process_matches: "noProcessing",
+                disabled: false,
+                grouped: false,
+                applies_to: ["main_frame"],
+            }
+        })
+        .collect();
+    let export = RedirectorExport {
+        created_by: format!("Fastside {VERSION}"),
+        created_at: Utc::now().to_rfc3339(),
+        redirects,
+    };
+    serde_json::to_string_pretty(&export).expect("failed to serialize export")
+}
+
+/// Escape unescaped `special` characters of AdGuard rule part with backslash.
+fn escape_adguard(value: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut escaped = false;
+    for c in value.chars() {
+        if special.contains(&c) && !escaped {
+            out.push('\\');
+        }
+        escaped = c == '\\' && !escaped;
+        out.push(c);
+    }
+    out
+}
+
+fn export_adguard(
+    services: &ServicesData,
+    crawled: &CrawledServices,
+    user_config: &UserConfig,
+) -> String {
+    let mut out = format!(
+        "! Title: Fastside redirects\n\
+         ! Description: Redirects to healthy instances of privacy-friendly frontends\n\
+         ! Version: {VERSION}\n\
+         ! Last modified: {}\n\
+         ! $urltransform rules only apply from trusted filter lists, e.g. user rules.\n",
+        Utc::now().to_rfc3339()
+    );
+    for rule in rules(services, crawled, user_config) {
+        let pattern = escape_adguard(&rule.regex, &['/']);
+        let regex = escape_adguard(&rule.regex, &['/', ',', '$']);
+        let replacement = escape_adguard(&rule.replacement, &['/', ',', '$']);
+        out.push_str(&format!(
+            "/{pattern}/$document,urltransform=/{regex}/{replacement}/\n"
+        ));
+    }
+    out
+}
+
+/// Export services with instances matching user config.
+pub fn export(
+    format: ExportFormat,
+    services: &ServicesData,
+    crawled: &CrawledServices,
+    user_config: &UserConfig,
+) -> String {
+    match format {
+        ExportFormat::Libredirect => export_libredirect(services, crawled, user_config),
+        ExportFormat::Redirector => export_redirector(services, crawled, user_config),
+        ExportFormat::Adguard => export_adguard(services, crawled, user_config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use fastside_shared::serde_types::StoredData;
+
+    use super::*;
+    use crate::crawler::{CrawledInstanceStatus, CrawledService};
+
+    #[test]
+    fn test_js_regex() {
+        assert_eq!(
+            js_regex(r"^(?P<host>example\.com)\(?x").as_deref(),
+            Some(r"^(?<host>example\.com)\(?x")
+        );
+        assert_eq!(js_regex(r"(?i)example\.com"), None);
+        assert_eq!(js_regex(r"\Aexample"), None);
+    }
+
+    #[test]
+    fn test_export() {
+        let stored: StoredData = serde_json::from_str(
+            r#"{"services": [{
+                "type": "facilmap",
+                "instances": [],
+                "regexes": [
+                    {"regex": "^(https?://)?maps\\.example\\.com(/.*)?$", "url": "$2",
+                     "examples": [{"input": "https://maps.example.com/x", "expected": "/x"}]},
+                    {"regex": "^(https?://)?m\\.example\\.com/(.*)$", "url": "/?q=?$2"}
+                ]
+            }]}"#,
+        )
+        .unwrap();
+        let services: ServicesData = stored
+            .services
+            .into_iter()
+            .map(|service| (service.name.clone(), service))
+            .collect();
+        let instance = |url: &str, tags: &[&str], ms| CrawledInstance {
+            url: url.parse().unwrap(),
+            status: CrawledInstanceStatus::Ok(Duration::from_millis(ms)),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            history: Default::default(),
+            location: None,
+            weight: 1,
+        };
+        let crawled = CrawledServices {
+            services: HashMap::from([(
+                "facilmap".to_string(),
+                CrawledService {
+                    name: "facilmap".to_string(),
+                    instances: vec![
+                        instance("https://slow.example/", &["clearnet"], 300),
+                        instance("https://fast.example/", &["clearnet"], 100),
+                        instance("http://onion.example/", &["tor"], 50),
+                    ],
+                },
+            )]),
+            time: Utc::now(),
+        };
+        let user_config = UserConfig {
+            required_tags: vec![],
+            ..Default::default()
+        };
+
+        let libredirect: serde_json::Value = serde_json::from_str(&export(
+            ExportFormat::Libredirect,
+            &services,
+            &crawled,
+            &user_config,
+        ))
+        .unwrap();
+        assert_eq!(
+            libredirect["facil"]["clearnet"],
+            serde_json::json!(["https://slow.example", "https://fast.example"])
+        );
+        assert_eq!(
+            libredirect["facil"]["tor"],
+            serde_json::json!(["http://onion.example"])
+        );
+
+        // Only the first regex is expressible, the second one url-encodes.
+        let rules = rules(&services, &crawled, &user_config);
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].replacement, "http://onion.example$2");
+
+        let user_config = UserConfig {
+            required_tags: vec!["clearnet".to_string()],
+            ..Default::default()
+        };
+        let redirector: serde_json::Value = serde_json::from_str(&export(
+            ExportFormat::Redirector,
+            &services,
+            &crawled,
+            &user_config,
+        ))
+        .unwrap();
+        let redirect = &redirector["redirects"][0];
+        assert_eq!(redirect["redirectUrl"], "https://fast.example$2");
+        assert_eq!(redirect["exampleResult"], "https://fast.example/x");
+
+        let adguard = export(ExportFormat::Adguard, &services, &crawled, &user_config);
+        assert!(adguard.contains(
+            r"/^(https?:\/\/)?maps\.example\.com(\/.*)?$/$document,urltransform=/^(https?:\/\/)?maps\.example\.com(\/.*)?\$/https:\/\/fast.example\$2/"
+        ), "{adguard}");
+    }
+}
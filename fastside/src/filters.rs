@@ -8,7 +8,7 @@ pub fn sort_crawled_instances(
     _env: &dyn askama::Values,
 ) -> askama::Result<Vec<CrawledInstance>> {
     let mut new = l.to_owned();
-    new.sort_by(|a, b| a.status.as_isize().cmp(&b.status.as_isize()));
+    new.sort_by_key(|i| i.status.as_isize());
     Ok(new)
 }
 
@@ -21,3 +21,15 @@ pub fn sort_crawled_services<'a>(
     new.sort_by(|a, b| a.1.name.cmp(&b.1.name));
     Ok(new.to_owned())
 }
+
+/// Serialize value as JSON to embed in `<script>`, used with `|safe`.
+///
+/// `<` is escaped, so strings can't close the script element.
+#[askama::filter_fn]
+pub fn script_json<T: serde::Serialize>(
+    value: &T,
+    _env: &dyn askama::Values,
+) -> askama::Result<String> {
+    let json = serde_json::to_string(value).map_err(|e| askama::Error::Custom(Box::new(e)))?;
+    Ok(json.replace('<', "\\u003c"))
+}
@@ -0,0 +1,280 @@
+//! GeoIP lookups from a local MaxMind-format database.
+//!
+//! Works with both Country and City databases. City databases also provide
+//! coordinates, which allow selecting instances by distance.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use actix_web::HttpRequest;
+use maxminddb::{MaxMindDbError, Reader};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::{Host, Url};
+
+use fastside_shared::config::GeoIpConfig;
+
+#[derive(Error, Debug)]
+pub enum GeoIpError {
+    #[error("database error: `{0}`")]
+    Database(#[from] MaxMindDbError),
+}
+
+/// Location of IP address.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GeoLocation {
+    /// ISO 3166-1 country code, lowercase.
+    pub country: Option<String>,
+    /// Continent code, lowercase.
+    pub continent: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+/// Approximate distances in km used when coordinates are unknown.
+const SAME_COUNTRY_DISTANCE: f64 = 0.0;
+const SAME_CONTINENT_DISTANCE: f64 = 2_000.0;
+const OTHER_CONTINENT_DISTANCE: f64 = 10_000.0;
+const UNKNOWN_DISTANCE: f64 = 20_000.0;
+
+const EARTH_RADIUS_KM: f64 = 6_371.0;
+
+impl GeoLocation {
+    /// Tags exposed on instances, e.g. `country-de` and `continent-eu`.
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags = Vec::with_capacity(2);
+        if let Some(country) = &self.country {
+            tags.push(format!("country-{country}"));
+        }
+        if let Some(continent) = &self.continent {
+            tags.push(format!("continent-{continent}"));
+        }
+        tags
+    }
+
+    /// Distance to other location in km.
+    ///
+    /// Uses coordinates if both locations have them, otherwise estimates
+    /// distance from country and continent.
+    pub fn distance(&self, other: &GeoLocation) -> f64 {
+        if let (Some(lat1), Some(lon1), Some(lat2), Some(lon2)) = (
+            self.latitude,
+            self.longitude,
+            other.latitude,
+            other.longitude,
+        ) {
+            // Haversine formula
+            let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+            let dlat = lat2 - lat1;
+            let dlon = (lon2 - lon1).to_radians();
+            let a =
+                (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+            return 2.0 * EARTH_RADIUS_KM * a.sqrt().asin();
+        }
+        match (
+            (&self.country, &other.country),
+            (&self.continent, &other.continent),
+        ) {
+            ((Some(a), Some(b)), _) if a == b => SAME_COUNTRY_DISTANCE,
+            (_, (Some(a), Some(b))) if a == b => SAME_CONTINENT_DISTANCE,
+            (_, (Some(_), Some(_))) => OTHER_CONTINENT_DISTANCE,
+            _ => UNKNOWN_DISTANCE,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IsoCode {
+    iso_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Code {
+    code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Location {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+/// Subset of GeoIP2 Country and City records.
+#[derive(Deserialize)]
+struct Record {
+    country: Option<IsoCode>,
+    continent: Option<Code>,
+    location: Option<Location>,
+}
+
+#[derive(Debug)]
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self, GeoIpError> {
+        Ok(Self {
+            reader: Reader::open_readfile(path)?,
+        })
+    }
+
+    /// Get location of IP address. Returns `None` if address is not in database.
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoLocation> {
+        let record: Record = match self.reader.lookup(ip).and_then(|r| r.decode()) {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(e) => {
+                debug!("GeoIP lookup for {ip} failed: {e}");
+                return None;
+            }
+        };
+        let location = record.location;
+        Some(GeoLocation {
+            country: record
+                .country
+                .and_then(|c| c.iso_code)
+                .map(|c| c.to_lowercase()),
+            continent: record
+                .continent
+                .and_then(|c| c.code)
+                .map(|c| c.to_lowercase()),
+            latitude: location.as_ref().and_then(|l| l.latitude),
+            longitude: location.as_ref().and_then(|l| l.longitude),
+        })
+    }
+
+    /// Resolve instance host and get its location.
+    ///
+    /// Hosts of overlay networks are never resolved.
+    pub async fn lookup_url(&self, url: &Url) -> Option<GeoLocation> {
+        let ip = match url.host()? {
+            Host::Ipv4(ip) => IpAddr::V4(ip),
+            Host::Ipv6(ip) => IpAddr::V6(ip),
+            Host::Domain(domain) => {
+                if [".onion", ".i2p", ".loki"]
+                    .iter()
+                    .any(|suffix| domain.ends_with(suffix))
+                {
+                    return None;
+                }
+                let port = url.port_or_known_default().unwrap_or(443);
+                tokio::net::lookup_host((domain, port))
+                    .await
+                    .ok()?
+                    .next()
+                    .map(|addr: SocketAddr| addr.ip())?
+            }
+        };
+        self.lookup(ip)
+    }
+}
+
+/// Get client IP address.
+///
+/// Trusted headers are checked in order, the last address of the first
+/// present header wins. Earlier addresses of lists like `X-Forwarded-For` are
+/// sent by the client and can be spoofed, the last one is appended by the
+/// proxy. Falls back to peer address.
+pub fn client_ip(req: &HttpRequest, config: &GeoIpConfig) -> Option<IpAddr> {
+    for header in &config.trusted_headers {
+        let Some(value) = req.headers().get_all(header.as_str()).last() else {
+            continue;
+        };
+        let last = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.rsplit(',').next());
+        if let Some(Ok(ip)) = last.map(|last| last.trim().parse()) {
+            return Some(ip);
+        }
+    }
+    req.peer_addr().map(|addr| addr.ip())
+}
+
+/// Distance from client to instance in km. Instances without location are
+/// treated as far away.
+pub fn instance_distance(client: &GeoLocation, instance: Option<&GeoLocation>) -> f64 {
+    instance.map_or(UNKNOWN_DISTANCE, |instance| client.distance(instance))
+}
+
+/// Get client location if GeoIP database is loaded.
+pub fn client_location(
+    req: &HttpRequest,
+    geoip: Option<&GeoIp>,
+    config: &GeoIpConfig,
+) -> Option<GeoLocation> {
+    geoip?.lookup(client_ip(req, config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn location(country: &str, continent: &str, coordinates: Option<(f64, f64)>) -> GeoLocation {
+        GeoLocation {
+            country: Some(country.to_string()),
+            continent: Some(continent.to_string()),
+            latitude: coordinates.map(|c| c.0),
+            longitude: coordinates.map(|c| c.1),
+        }
+    }
+
+    #[test]
+    fn test_distance() {
+        let berlin = location("de", "eu", Some((52.52, 13.405)));
+        let paris = location("fr", "eu", Some((48.8566, 2.3522)));
+        let distance = berlin.distance(&paris);
+        assert!((870.0..890.0).contains(&distance), "{distance}");
+
+        let tokyo = location("jp", "as", None);
+        let osaka = location("jp", "as", None);
+        assert_eq!(tokyo.distance(&osaka), SAME_COUNTRY_DISTANCE);
+        assert_eq!(tokyo.distance(&berlin), OTHER_CONTINENT_DISTANCE);
+        assert_eq!(tokyo.distance(&GeoLocation::default()), UNKNOWN_DISTANCE);
+        assert_eq!(tokyo.tags(), vec!["country-jp", "continent-as"]);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let config = GeoIpConfig {
+            database: None,
+            trusted_headers: vec![
+                "CF-Connecting-IP".to_string(),
+                "X-Forwarded-For".to_string(),
+            ],
+        };
+        // Client sent its own header, proxy appended the real address.
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-For", "198.51.100.7, 203.0.113.1"))
+            .peer_addr("127.0.0.1:1234".parse().unwrap())
+            .to_http_request();
+        assert_eq!(
+            client_ip(&req, &config),
+            Some("203.0.113.1".parse().unwrap())
+        );
+
+        let req = TestRequest::default()
+            .append_header(("X-Forwarded-For", "198.51.100.7"))
+            .append_header(("X-Forwarded-For", "203.0.113.1"))
+            .peer_addr("127.0.0.1:1234".parse().unwrap())
+            .to_http_request();
+        assert_eq!(
+            client_ip(&req, &config),
+            Some("203.0.113.1".parse().unwrap())
+        );
+
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-For", "203.0.113.1"))
+            .peer_addr("127.0.0.1:1234".parse().unwrap())
+            .to_http_request();
+        let untrusted = GeoIpConfig::default();
+        assert_eq!(
+            client_ip(&req, &untrusted),
+            Some("127.0.0.1".parse().unwrap())
+        );
+    }
+}
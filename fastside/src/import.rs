@@ -0,0 +1,293 @@
+//! Import of settings exported from the LibRedirect browser extension.
+//!
+//! Both current exports, with per-service objects like
+//! `"youtube": {"frontend": "invidious", ...}` and per-frontend instance lists
+//! like `"invidious": ["https://..."]`, and older exports with keys like
+//! `youtubeFrontend` and `invidiousNormalRedirectsChecks` are understood.
+//! Everything that has no counterpart in [`UserConfig`] is reported.
+
+use fastside_shared::{
+    config::{SelectMethod, UserConfig},
+    matcher::{GroupIndex, InstanceIndex},
+    serde_types::ServicesData,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use url::Url;
+
+/// LibRedirect frontend names which differ from Fastside service names, as
+/// (LibRedirect, Fastside) pairs.
+pub const LIBREDIRECT_NAMES: &[(&str, &str)] = &[
+    ("facil", "facilmap"),
+    ("pipedMaterial", "pipedmaterial"),
+    ("libMedium", "libmedium"),
+    ("breezeWiki", "breezewiki"),
+    ("simplyTranslate", "simplytranslate"),
+    ("anonymousOverflow", "anonymousoverflow"),
+    ("biblioReads", "biblioreads"),
+];
+
+/// Suffixes of instance list keys in older exports, with network of the list.
+const LEGACY_INSTANCE_SUFFIXES: &[(&str, &str)] = &[
+    ("NormalRedirectsChecks", "clearnet"),
+    ("NormalCustomRedirects", "clearnet"),
+    ("TorRedirectsChecks", "tor"),
+    ("TorCustomRedirects", "tor"),
+    ("I2pRedirectsChecks", "i2p"),
+    ("I2pCustomRedirects", "i2p"),
+    ("LokiRedirectsChecks", "loki"),
+    ("LokiCustomRedirects", "loki"),
+];
+
+/// Setting which could not be represented in [`UserConfig`].
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Unsupported {
+    pub setting: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Imported {
+    pub config: UserConfig,
+    pub unsupported: Vec<Unsupported>,
+}
+
+struct Importer<'a> {
+    services: &'a ServicesData,
+    groups: &'a GroupIndex,
+    instances: &'a InstanceIndex,
+    config: UserConfig,
+    unsupported: Vec<Unsupported>,
+}
+
+impl Importer<'_> {
+    fn unsupported(&mut self, setting: impl Into<String>, reason: impl Into<String>) {
+        self.unsupported.push(Unsupported {
+            setting: setting.into(),
+            reason: reason.into(),
+        });
+    }
+
+    /// Fastside service name of LibRedirect frontend.
+    fn resolve_frontend(&self, frontend: &str) -> Option<String> {
+        let frontend = LIBREDIRECT_NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(frontend))
+            .map_or(frontend, |(_, service)| service);
+        self.services
+            .values()
+            .find(|service| {
+                service.name.eq_ignore_ascii_case(frontend)
+                    || service
+                        .aliases
+                        .iter()
+                        .any(|alias| alias.eq_ignore_ascii_case(frontend))
+            })
+            .map(|service| service.name.clone())
+    }
+
+    fn import_network(&mut self, key: &str, value: &Value) {
+        let tags = match value.as_str() {
+            Some("clearnet" | "normal") => return,
+            Some(network @ ("tor" | "i2p")) => vec![network.to_string()],
+            Some(network) => {
+                return self.unsupported(key, format!("no instances are tagged `{network}`"));
+            }
+            None => return self.unsupported(key, "expected network name"),
+        };
+        self.config.required_tags = tags;
+    }
+
+    /// Select frontend of LibRedirect service, which is a Fastside group.
+    fn import_frontend(&mut self, key: &str, frontend: &str) {
+        let Some(service) = self.resolve_frontend(frontend) else {
+            return self.unsupported(key, format!("frontend `{frontend}` is not known"));
+        };
+        if let Some(group) = self.groups.for_service(&service) {
+            self.config
+                .group_preferences
+                .insert(group.name.clone(), vec![service]);
+        }
+    }
+
+    fn import_service(&mut self, name: &str, options: &Map<String, Value>) {
+        for (key, value) in options {
+            let setting = format!("{name}.{key}");
+            match (key.as_str(), value) {
+                ("frontend", Value::String(frontend)) => self.import_frontend(&setting, frontend),
+                ("enabled", Value::Bool(true)) | ("instance", _) => {}
+                ("enabled", _) => {
+                    self.unsupported(setting, "redirects cannot be disabled per service")
+                }
+                _ => self.unsupported(setting, "not supported by Fastside"),
+            }
+        }
+    }
+
+    /// Prefer known instances of the frontend and add unknown ones as
+    /// custom instances.
+    fn import_instances(&mut self, key: &str, frontend: &str, urls: &[Value]) {
+        let Some(service) = self.resolve_frontend(frontend) else {
+            return self.unsupported(key, format!("frontend `{frontend}` is not known"));
+        };
+        for url in urls {
+            let Some(url) = url.as_str().and_then(|url| Url::parse(url).ok()) else {
+                self.unsupported(format!("{key}: {url}"), "invalid instance URL");
+                continue;
+            };
+            match (self.instances.find(&url), url.host_str()) {
+                (Some(_), Some(host)) => {
+                    let host = host.to_string();
+                    if !self.config.preferred_instances.contains(&host) {
+                        self.config.preferred_instances.push(host);
+                    }
+                }
+                _ => {
+                    let custom = self
+                        .config
+                        .custom_instances
+                        .entry(service.clone())
+                        .or_default();
+                    if !custom.contains(&url) {
+                        custom.push(url);
+                    }
+                }
+            }
+        }
+    }
+
+    fn import_setting(&mut self, key: &str, value: &Value) {
+        if key == "network" {
+            return self.import_network(key, value);
+        }
+        if key == "networkFallback" {
+            if value.as_bool() == Some(true) {
+                self.unsupported(
+                    key,
+                    "instances of other networks are never used, service fallback is used instead",
+                );
+            }
+            return;
+        }
+        match value {
+            Value::Object(options) if options.contains_key("frontend") => {
+                return self.import_service(key, options);
+            }
+            Value::Array(urls) if self.resolve_frontend(key).is_some() => {
+                return self.import_instances(key, key, urls);
+            }
+            Value::String(frontend) if key.ends_with("Frontend") => {
+                return self.import_frontend(key, frontend);
+            }
+            _ => {}
+        }
+        if let Value::Array(urls) = value {
+            for (suffix, network) in LEGACY_INSTANCE_SUFFIXES {
+                let Some(frontend) = key.strip_suffix(suffix) else {
+                    continue;
+                };
+                if *network == "loki" && !urls.is_empty() {
+                    return self.unsupported(key, "no instances are tagged `loki`");
+                }
+                return self.import_instances(key, frontend, urls);
+            }
+        }
+        self.unsupported(key, "not supported by Fastside")
+    }
+}
+
+/// Convert LibRedirect `settings` export to user config based on `base`.
+pub fn import_libredirect(
+    settings: &Map<String, Value>,
+    base: &UserConfig,
+    services: &ServicesData,
+    groups: &GroupIndex,
+    instances: &InstanceIndex,
+) -> Imported {
+    let mut importer = Importer {
+        services,
+        groups,
+        instances,
+        config: base.clone(),
+        unsupported: Vec::new(),
+    };
+    // LibRedirect picks random instance out of selected ones.
+    importer.config.select_method = SelectMethod::Random;
+    for (key, value) in settings {
+        importer.import_setting(key, value);
+    }
+    Imported {
+        config: importer.config,
+        unsupported: importer.unsupported,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastside_shared::serde_types::StoredData;
+
+    #[test]
+    fn test_import_libredirect() {
+        let stored: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "invidious", "instances": [{"url": "https://inv.example.com", "tags": []}]},
+                {"type": "piped", "instances": []},
+                {"type": "libreddit", "aliases": ["redlib"], "instances": []}
+            ], "groups": [
+                {"name": "youtube", "services": [{"service": "invidious"}, {"service": "piped"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let services: ServicesData = stored
+            .services
+            .iter()
+            .map(|service| (service.name.clone(), service.clone()))
+            .collect();
+        let groups = GroupIndex::new(&stored.groups, &stored.services).unwrap();
+        let instances = InstanceIndex::new(&stored.services);
+        let settings: Map<String, Value> = serde_json::from_str(
+            r#"{
+                "youtube": {"enabled": true, "frontend": "piped", "embedFrontend": "invidious"},
+                "reddit": {"enabled": false, "frontend": "redlib"},
+                "invidious": ["https://inv.example.com", "https://own.example.org"],
+                "network": "tor",
+                "theme": "dark",
+                "nitterNormalRedirectsChecks": ["https://nitter.example.com"]
+            }"#,
+        )
+        .unwrap();
+
+        let imported = import_libredirect(
+            &settings,
+            &UserConfig::serde_default(),
+            &services,
+            &groups,
+            &instances,
+        );
+        let config = &imported.config;
+        assert_eq!(config.required_tags, vec!["tor"]);
+        assert_eq!(config.group_preferences["youtube"], vec!["piped"]);
+        assert_eq!(config.preferred_instances, vec!["inv.example.com"]);
+        assert_eq!(
+            config.custom_instances["invidious"],
+            vec![Url::parse("https://own.example.org").unwrap()]
+        );
+
+        let mut unsupported: Vec<&str> = imported
+            .unsupported
+            .iter()
+            .map(|u| u.setting.as_str())
+            .collect();
+        unsupported.sort_unstable();
+        assert_eq!(
+            unsupported,
+            vec![
+                "nitterNormalRedirectsChecks",
+                "reddit.enabled",
+                "theme",
+                "youtube.embedFrontend"
+            ]
+        );
+    }
+}
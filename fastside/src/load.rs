@@ -0,0 +1,143 @@
+//! Time-decayed counters of redirects per instance.
+//!
+//! Counters live in memory only and are shared between workers. Each counter
+//! decays exponentially, so old redirects matter less than recent ones.
+//! Counters decayed to almost zero are dropped, so instances removed from
+//! services or custom instances do not stay forever.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+/// Counters below this value are dropped when pruning.
+const MIN_LOAD: f64 = 1e-3;
+
+#[derive(Debug)]
+struct Counter {
+    value: f64,
+    updated: Instant,
+}
+
+impl Counter {
+    fn value_at(&self, now: Instant, half_life: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.value * 0.5f64.powf(elapsed / half_life.as_secs_f64())
+    }
+}
+
+#[derive(Debug)]
+struct Counters {
+    by_url: HashMap<Url, Counter>,
+    /// Last time decayed counters were dropped.
+    pruned: Instant,
+}
+
+#[derive(Debug)]
+pub struct LoadTracker {
+    half_life: Duration,
+    counters: Mutex<Counters>,
+}
+
+impl LoadTracker {
+    pub fn new(half_life: Duration) -> Self {
+        Self {
+            // Zero half-life would divide by zero.
+            half_life: half_life.max(Duration::from_secs(1)),
+            counters: Mutex::new(Counters {
+                by_url: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Record redirects to instance. `amount` may be fractional if instance
+    /// is chosen on client side.
+    pub fn record(&self, url: &Url, amount: f64) {
+        self.record_at(url, amount, Instant::now())
+    }
+
+    /// Decayed counts of recent redirects to instances, read at once.
+    pub fn loads<'a>(&self, urls: impl IntoIterator<Item = &'a Url>) -> Vec<f64> {
+        self.loads_at(urls, Instant::now())
+    }
+
+    fn record_at(&self, url: &Url, amount: f64, now: Instant) {
+        let mut counters = self.counters.lock().unwrap();
+        // Prune at most once per half-life, so recording stays cheap.
+        if now.saturating_duration_since(counters.pruned) >= self.half_life {
+            counters
+                .by_url
+                .retain(|_, c| c.value_at(now, self.half_life) >= MIN_LOAD);
+            counters.pruned = now;
+        }
+        let value = counters
+            .by_url
+            .get(url)
+            .map_or(0.0, |c| c.value_at(now, self.half_life));
+        counters.by_url.insert(
+            url.clone(),
+            Counter {
+                value: value + amount,
+                updated: now,
+            },
+        );
+    }
+
+    fn loads_at<'a>(&self, urls: impl IntoIterator<Item = &'a Url>, now: Instant) -> Vec<f64> {
+        let counters = self.counters.lock().unwrap();
+        urls.into_iter()
+            .map(|url| {
+                counters
+                    .by_url
+                    .get(url)
+                    .map_or(0.0, |c| c.value_at(now, self.half_life))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decay() {
+        let tracker = LoadTracker::new(Duration::from_secs(60));
+        let url = Url::parse("https://example.com/").unwrap();
+        let start = Instant::now();
+
+        tracker.record_at(&url, 4.0, start);
+        assert_eq!(tracker.loads_at([&url], start)[0], 4.0);
+        let later = start + Duration::from_secs(60);
+        assert!((tracker.loads_at([&url], later)[0] - 2.0).abs() < 1e-9);
+
+        tracker.record_at(&url, 1.0, later);
+        let even_later = later + Duration::from_secs(120);
+        assert!((tracker.loads_at([&url], even_later)[0] - 0.75).abs() < 1e-9);
+
+        let other = Url::parse("https://other.example.com/").unwrap();
+        assert_eq!(tracker.loads_at([&other], start)[0], 0.0);
+    }
+
+    #[test]
+    fn test_prune() {
+        let tracker = LoadTracker::new(Duration::from_secs(60));
+        let url = Url::parse("https://example.com/").unwrap();
+        let other = Url::parse("https://other.example.com/").unwrap();
+        let start = Instant::now();
+
+        tracker.record_at(&url, 1.0, start);
+        tracker.record_at(&other, 1.0, start + Duration::from_secs(60));
+        assert_eq!(tracker.counters.lock().unwrap().by_url.len(), 2);
+
+        // Both decay below minimum, only the recorded one is kept.
+        tracker.record_at(&other, 1.0, start + Duration::from_secs(60 * 20));
+        let counters = tracker.counters.lock().unwrap();
+        assert_eq!(counters.by_url.len(), 1);
+        assert!(counters.by_url.contains_key(&other));
+    }
+}
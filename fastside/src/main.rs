@@ -1,35 +1,48 @@
 //! Fastside API server.
 mod crawler;
 mod errors;
+mod export;
 mod filters;
+mod geoip;
+mod import;
+mod load;
 mod routes;
 mod search;
+mod short_codes;
 mod types;
 mod utils;
 
-use actix_web::{App, HttpServer, middleware::Logger, web};
+use actix_web::{
+    App, HttpServer,
+    middleware::{Logger, from_fn},
+    web,
+};
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use config::load_config;
 use crawler::Crawler;
+use export::ExportFormat;
 use fastside_shared::{
     config::{self, AppConfig},
     errors::CliError,
     log_setup,
+    matcher::{
+        DomainIndex, GroupIndex, InstanceIndex, ReplacementMatcher, ReverseMatcher, UrlMatcher,
+    },
     serde_types::{ServicesData, StoredData},
 };
+use geoip::GeoIp;
+use load::LoadTracker;
 use log_setup::configure_logging;
-use regex::Regex;
-use routes::main_scope;
+use routes::{generation_header, main_scope};
+use short_codes::ShortCodeStore;
 use std::{
-    collections::HashMap,
     net::{SocketAddr, SocketAddrV4},
     path::PathBuf,
     str::FromStr,
     sync::Arc,
 };
-use tokio::sync::RwLock;
-use types::{CompiledRegexSearch, LoadedData};
+use types::{LoadedData, LoadedDataStore};
 use url::Url;
 
 #[deny(unused_imports)]
@@ -86,6 +99,23 @@ enum Commands {
         #[arg(short, long)]
         services: Option<String>,
     },
+    /// Export healthy instances as redirect rules for browser extensions.
+    Export {
+        /// Export format.
+        format: ExportFormat,
+        /// Services path.
+        #[arg(short, long)]
+        services: Option<String>,
+        /// Path to ping data file to take instance status from. Instances are crawled if not set.
+        #[arg(long)]
+        ping_data_file: Option<PathBuf>,
+        /// Config string to filter instances with. Default user config is used if not set.
+        #[arg(long)]
+        user_config: Option<String>,
+        /// Output path. Export is written to stdout if not set.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
 // This function is needed to take ownership over cloned reference to crawler.
@@ -138,15 +168,40 @@ async fn load_services(source: &ServicesSource, config: &AppConfig) -> Result<Lo
     let data_content = load_services_data(source).await?;
     let stored_data: StoredData =
         serde_json::from_str(&data_content).context("failed to parse services file")?;
+    let reserved = stored_data.reserved_names();
+    if !reserved.is_empty() {
+        return Err(anyhow::anyhow!(
+            "services file uses names reserved by redirect routes: {}",
+            reserved.join(", ")
+        ));
+    }
     let services_data: ServicesData = stored_data
         .services
         .into_iter()
         .map(|service| (service.name.clone(), service))
         .collect();
+    let matcher = UrlMatcher::new(services_data.values()).context("failed to build URL matcher")?;
+    let instance_index = InstanceIndex::new(services_data.values());
+    let domains = DomainIndex::new(services_data.values());
+    let reverse_matcher =
+        ReverseMatcher::new(services_data.values()).context("failed to build reverse matcher")?;
+    let replacements = ReplacementMatcher::new(services_data.values())
+        .context("failed to build replacement matcher")?;
+    let groups = GroupIndex::new(&stored_data.groups, services_data.values())
+        .context("failed to build group matchers")?;
     Ok(LoadedData {
+        // Generation and load time are assigned by LoadedDataStore.
+        generation: 0,
+        loaded_at: chrono::DateTime::UNIX_EPOCH,
         services: services_data,
         proxies: config.proxies.clone(),
         default_user_config: config.default_user_config.clone(),
+        matcher,
+        instance_index,
+        domains,
+        reverse_matcher,
+        replacements,
+        groups,
     })
 }
 
@@ -155,7 +210,7 @@ async fn reload_services(
     source: &ServicesSource,
     config: Arc<AppConfig>,
     crawler: Arc<Crawler>,
-    data: Arc<RwLock<LoadedData>>,
+    data: Arc<LoadedDataStore>,
 ) -> Result<()> {
     let reload_interval = config.auto_updater.interval.as_secs();
     match &source {
@@ -174,11 +229,17 @@ async fn reload_services(
                         .context("failed to get modified time")?
                 {
                     info!("Reloading services file");
-                    let new_data = load_services(source, &config)
-                        .await
-                        .context("failed to load services")?;
-                    *data.write().await = new_data;
                     file_stat = new_file_stat;
+                    // Broken services file must not take down working data.
+                    let new_data = match load_services(source, &config).await {
+                        Ok(new_data) => new_data,
+                        Err(e) => {
+                            error!("Failed to load services, keeping old data: {:#}", e);
+                            continue;
+                        }
+                    };
+                    let generation = data.replace(new_data).await;
+                    info!("Loaded services generation {}", generation);
                     crawler
                         .update_crawl(None)
                         .await
@@ -211,11 +272,17 @@ async fn reload_services(
                 debug!("Etag: {}", etag);
                 if new_etag != etag {
                     info!("Reloading services file");
-                    let new_data = load_services(source, &config)
-                        .await
-                        .context("failed to load services")?;
-                    *data.write().await = new_data;
                     etag = new_etag;
+                    // Broken services file must not take down working data.
+                    let new_data = match load_services(source, &config).await {
+                        Ok(new_data) => new_data,
+                        Err(e) => {
+                            error!("Failed to load services, keeping old data: {:#}", e);
+                            continue;
+                        }
+                    };
+                    let generation = data.replace(new_data).await;
+                    info!("Loaded services generation {}", generation);
                     crawler
                         .update_crawl(None)
                         .await
@@ -230,7 +297,7 @@ async fn reload_services_wrapper(
     source: ServicesSource,
     config: Arc<AppConfig>,
     crawler: Arc<Crawler>,
-    data: Arc<RwLock<LoadedData>>,
+    data: Arc<LoadedDataStore>,
 ) {
     if !config.auto_updater.enabled {
         debug!("Auto updater is disabled");
@@ -313,34 +380,25 @@ async fn main() -> Result<()> {
                 .unwrap_or_else(|| SocketAddr::V4(SocketAddrV4::new([127, 0, 0, 1].into(), 8080)));
             let workers: usize = workers.unwrap_or_else(num_cpus::get);
 
-            let data: Arc<RwLock<LoadedData>> = {
+            let data: Arc<LoadedDataStore> = {
                 let data = load_services(&services_source, &config).await?;
-                Arc::new(RwLock::new(data))
+                Arc::new(LoadedDataStore::new(data))
             };
-            let regexes: HashMap<String, Vec<CompiledRegexSearch>> = data
-                .read()
-                .await
-                .services
-                .iter()
-                .filter_map(|(name, service)| {
-                    let regexes = service
-                        .regexes
-                        .iter()
-                        .map(|regex| {
-                            let compiled = Regex::new(&regex.regex)
-                                .context(format!("failed to compile regex for {}", name))
-                                .ok()?;
-                            Some(CompiledRegexSearch {
-                                regex: compiled,
-                                url: regex.url.clone(),
-                            })
-                        })
-                        .collect::<Option<Vec<CompiledRegexSearch>>>()?;
-                    Some((name.clone(), regexes))
-                })
-                .collect();
-
-            let crawler = Arc::new(Crawler::new(data.clone(), config.crawler.clone()));
+
+            let geoip: Option<Arc<GeoIp>> = match &config.geoip.database {
+                Some(path) => {
+                    let geoip = GeoIp::open(path).context("failed to open GeoIP database")?;
+                    info!("Loaded GeoIP database: {:?}", path);
+                    Some(Arc::new(geoip))
+                }
+                None => None,
+            };
+
+            let crawler = Arc::new(Crawler::new(
+                data.clone(),
+                config.crawler.clone(),
+                geoip.clone(),
+            ));
 
             // Initialize crawler based on ping data availability and skip-wait setting
             let mut initialized_from_ping_data = false;
@@ -384,21 +442,29 @@ async fn main() -> Result<()> {
                 data.clone(),
             ));
 
+            let short_codes = ShortCodeStore::open(&config.short_codes)
+                .context("failed to open short codes store")?;
+
             info!("Listening on {}", listen);
 
             let config_web_data = web::Data::from(config.clone());
             let crawler_web_data = web::Data::from(crawler.clone());
             let data_web_data = web::Data::from(data.clone());
-            let regexes_web_data = web::Data::new(regexes);
+            let geoip_web_data = web::Data::new(geoip);
+            let load_web_data = web::Data::new(LoadTracker::new(config.load.half_life));
+            let short_codes_web_data = web::Data::new(short_codes);
 
             HttpServer::new(move || {
                 let logger = Logger::default();
                 App::new()
+                    .wrap(from_fn(generation_header))
                     .wrap(logger)
                     .app_data(config_web_data.clone())
                     .app_data(crawler_web_data.clone())
                     .app_data(data_web_data.clone())
-                    .app_data(regexes_web_data.clone())
+                    .app_data(geoip_web_data.clone())
+                    .app_data(load_web_data.clone())
+                    .app_data(short_codes_web_data.clone())
                     .service(main_scope(&config.clone()))
             })
             .bind(listen)?
@@ -437,6 +503,58 @@ async fn main() -> Result<()> {
                 info!("{}", validation_result.format());
             }
 
+            return Ok(());
+        }
+        Some(Commands::Export {
+            format,
+            services,
+            ping_data_file,
+            user_config,
+            output,
+        }) => {
+            let config = load_config(&cli.config).context("failed to load config")?;
+            let services_source = ServicesSource::from_str(
+                &services
+                    .clone()
+                    .or(config.services.clone())
+                    .unwrap_or_else(|| String::from("services.json")),
+            )?;
+            debug!("Using services source: {:?}", services_source);
+
+            let user_config = match user_config {
+                Some(config_string) => config::UserConfig::from_config_string(config_string)
+                    .context("failed to parse user config")?,
+                None => config.default_user_config.clone(),
+            };
+
+            let data = Arc::new(LoadedDataStore::new(
+                load_services(&services_source, &config).await?,
+            ));
+            let crawler = Crawler::new(data.clone(), config.crawler.clone(), None);
+            let loaded = match ping_data_file {
+                Some(path) => crawler
+                    .load_ping_data_from_file(path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!(e))
+                    .context("failed to load ping data")?,
+                None => false,
+            };
+            if !loaded {
+                info!("Crawling instances");
+                crawler.update_crawl(None).await?;
+            }
+
+            let services_data = data.snapshot().await;
+            let crawled = crawler.read().await;
+            let crawled = crawled
+                .get_services()
+                .context("no crawled services available")?;
+            let export = export::export(*format, &services_data.services, crawled, &user_config);
+            match output {
+                Some(path) => std::fs::write(path, export).context("failed to write export")?,
+                None => print!("{export}"),
+            }
+
             return Ok(());
         }
     };
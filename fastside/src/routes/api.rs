@@ -1,20 +1,42 @@
-use actix_web::{Responder, Scope, post, web};
+use std::sync::Arc;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, Scope, get, post, web};
+use chrono::{DateTime, Utc};
 use fastside_shared::config::UserConfig;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
 
 use crate::{
     config::AppConfig,
     crawler::Crawler,
     errors::{RedirectApiError, RedirectError},
-    types::{LoadedData, Regexes},
+    export::{ExportFormat, export},
+    geoip::{GeoIp, client_location},
+    import::{Unsupported, import_libredirect},
+    load::LoadTracker,
+    search::{Replacement, SearchError, find_original_url},
+    short_codes::ShortCodeStore,
+    types::LoadedDataStore,
+    utils::{
+        url::{compose_redirect_url, url_query},
+        user_config::load_user_config,
+    },
 };
 
+use super::request_snapshot;
+
 pub fn scope(_config: &AppConfig) -> Scope {
     web::scope("/api/v1")
         .service(redirect)
+        .service(unredirect)
         .service(make_user_config_string)
         .service(parse_user_config_string)
+        .service(create_short_code)
+        .service(get_short_code)
+        .service(import_config)
+        .service(export_rules)
+        .service(super::services::list_services)
+        .service(super::services::get_service)
+        .service(super::services::list_instances)
 }
 
 #[derive(Deserialize)]
@@ -28,28 +50,91 @@ struct RedirectRequest {
 struct RedirectResponse {
     url: String,
     is_fallback: bool,
+    /// Set if requested service is deprecated and was replaced.
+    replacement: Option<Replacement>,
+    /// Generation of services data used to compute the redirect.
+    generation: u64,
 }
 
 /// Get the redirect URL for a given URL
 #[post("/redirect")]
 async fn redirect(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
     crawler: web::Data<Crawler>,
-    loaded_data: web::Data<RwLock<LoadedData>>,
-    regexes: web::Data<Regexes>,
+    loaded_data: web::Data<LoadedDataStore>,
+    geoip: web::Data<Option<Arc<GeoIp>>>,
+    load: web::Data<LoadTracker>,
     redirect_request: web::Json<RedirectRequest>,
 ) -> actix_web::Result<impl Responder> {
-    let loaded_data_guard = loaded_data.read().await;
-    let (url, is_fallback) = super::redirect::find_redirect(
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let client = client_location(&req, geoip.get_ref().as_deref(), &config.geoip);
+    let found = super::redirect::find_redirect(
         crawler.as_ref(),
         &loaded_data_guard,
-        regexes.as_ref(),
         &redirect_request.config,
+        client.as_ref(),
+        &load,
         &redirect_request.url,
     )
     .await
     .map_err(RedirectApiError)?;
+    found.record(&load);
+    // Query of the URL is kept like request query on redirect routes.
+    let url = compose_redirect_url(
+        &found.url,
+        super::redirect::forwarded_query(
+            &loaded_data_guard,
+            &redirect_request.url,
+            url_query(&redirect_request.url),
+        ),
+        None,
+        config.query_precedence,
+    )
+    .map_err(|e| RedirectApiError(RedirectError::from(e)))?;
+
+    Ok(web::Json(RedirectResponse {
+        url: url.to_string(),
+        is_fallback: found.is_fallback,
+        replacement: found.replacement,
+        generation: loaded_data_guard.generation,
+    }))
+}
+
+#[derive(Deserialize)]
+struct UnredirectRequest {
+    url: String,
+}
 
-    Ok(web::Json(RedirectResponse { url, is_fallback }))
+#[derive(Serialize)]
+struct UnredirectResponse {
+    url: String,
+    service: String,
+    /// Generation of services data used to compute the original URL.
+    generation: u64,
+}
+
+/// Get the original URL for a URL on one of known instances
+#[post("/unredirect")]
+async fn unredirect(
+    req: HttpRequest,
+    loaded_data: web::Data<LoadedDataStore>,
+    unredirect_request: web::Json<UnredirectRequest>,
+) -> actix_web::Result<impl Responder> {
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let (service, url) = find_original_url(
+        &loaded_data_guard.instance_index,
+        &loaded_data_guard.reverse_matcher,
+        &unredirect_request.url,
+    )
+    .map_err(RedirectError::from)
+    .map_err(RedirectApiError)?;
+
+    Ok(web::Json(UnredirectResponse {
+        url: url.to_string(),
+        service: service.to_string(),
+        generation: loaded_data_guard.generation,
+    }))
 }
 
 /// Convert user config to a base64 encoded string
@@ -76,3 +161,106 @@ async fn parse_user_config_string(
             .map_err(RedirectApiError)?,
     ))
 }
+
+#[derive(Serialize)]
+struct ShortCodeResponse {
+    code: String,
+    /// Path applying config as cookie, or prefix of redirect paths using it inline.
+    path: String,
+    expires: DateTime<Utc>,
+}
+
+/// Store user config under short code
+#[post("/config")]
+async fn create_short_code(
+    short_codes: web::Data<ShortCodeStore>,
+    user_config: web::Json<UserConfig>,
+) -> actix_web::Result<impl Responder> {
+    let (code, expires) = short_codes
+        .create(&user_config)
+        .await
+        .map_err(RedirectError::from)
+        .map_err(RedirectApiError)?;
+    Ok(web::Json(ShortCodeResponse {
+        path: format!("/c/{code}"),
+        code,
+        expires,
+    }))
+}
+
+/// Get user config stored under short code
+#[get("/config/{code}")]
+async fn get_short_code(
+    code: web::Path<String>,
+    short_codes: web::Data<ShortCodeStore>,
+) -> actix_web::Result<impl Responder> {
+    Ok(web::Json(
+        short_codes
+            .get(&code)
+            .map_err(RedirectError::from)
+            .map_err(RedirectApiError)?,
+    ))
+}
+
+#[derive(Serialize)]
+struct ImportResponse {
+    config: UserConfig,
+    config_string: String,
+    /// Settings which could not be represented in user config.
+    unsupported: Vec<Unsupported>,
+}
+
+/// Convert LibRedirect settings export to user config
+#[post("/import_config")]
+async fn import_config(
+    req: HttpRequest,
+    loaded_data: web::Data<LoadedDataStore>,
+    settings: web::Json<serde_json::Map<String, serde_json::Value>>,
+) -> actix_web::Result<impl Responder> {
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let imported = import_libredirect(
+        &settings,
+        &loaded_data_guard.default_user_config,
+        &loaded_data_guard.services,
+        &loaded_data_guard.groups,
+        &loaded_data_guard.instance_index,
+    );
+    Ok(web::Json(ImportResponse {
+        config_string: imported
+            .config
+            .to_config_string()
+            .map_err(RedirectError::from)
+            .map_err(RedirectApiError)?,
+        config: imported.config,
+        unsupported: imported.unsupported,
+    }))
+}
+
+/// Export healthy instances as redirect rules for browser extensions
+///
+/// Instances are filtered by user config from `fs_config` query parameter or
+/// cookie.
+#[get("/export/{format}")]
+async fn export_rules(
+    req: HttpRequest,
+    format: web::Path<ExportFormat>,
+    crawler: web::Data<Crawler>,
+    loaded_data: web::Data<LoadedDataStore>,
+) -> actix_web::Result<impl Responder> {
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let user_config =
+        load_user_config(&req, &loaded_data_guard.default_user_config).map_err(RedirectApiError)?;
+    let data = crawler.read().await;
+    let crawled_services = data
+        .get_services()
+        .ok_or(RedirectError::from(SearchError::CrawlerNotFetchedYet))
+        .map_err(RedirectApiError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(export(
+            *format,
+            &loaded_data_guard.services,
+            crawled_services,
+            &user_config,
+        )))
+}
@@ -1,60 +1,242 @@
-use actix_web::{HttpRequest, Responder, Scope, cookie::Cookie, get, http::header::LOCATION, web};
+use std::collections::BTreeMap;
+
+use actix_web::{
+    HttpRequest, Responder, Scope,
+    cookie::Cookie,
+    get,
+    http::header::{self, LOCATION},
+    post, web,
+};
 use askama::Template;
-use fastside_shared::config::UserConfig;
-use tokio::sync::RwLock;
+use base64::prelude::*;
+use fastside_shared::{config::UserConfig, config_string};
 
 use crate::{
-    config::AppConfig, errors::RedirectError, types::LoadedData,
-    utils::user_config::load_settings_cookie,
+    config::AppConfig,
+    errors::RedirectError,
+    short_codes::ShortCodeStore,
+    types::LoadedDataStore,
+    utils::user_config::{load_settings_cookie, load_user_config},
 };
 
+use super::request_snapshot;
+
 pub fn scope(_config: &AppConfig) -> Scope {
     web::scope("/configure")
         .service(configure_page)
         .service(configure_save)
+        .service(configure_save_confirmed)
+}
+
+#[derive(Template)]
+#[template(path = "confirm_config.html")]
+pub struct ConfirmConfigTemplate<'a> {
+    /// Config string to save.
+    config: &'a str,
+    /// Service names and URLs of custom instances, sorted.
+    custom_instances: &'a [(&'a str, &'a url::Url)],
 }
 
 #[derive(Template)]
 #[template(path = "configure.html")]
 pub struct ConfigureTemplate<'a> {
+    /// Base64-encoded JSON of current config with all fields.
     current_config: &'a str,
+    /// Base64-encoded JSON of config with serde defaults, which may be left
+    /// out of config strings.
+    default_config: &'a str,
+    /// Why config cookie was ignored.
+    cookie_error: Option<String>,
+    /// JSON object of group names to their services in group order.
+    groups: &'a str,
+    /// JSON array of service names, sorted.
+    services: &'a str,
+    short_codes_enabled: bool,
 }
 
 #[get("")]
 async fn configure_page(
     req: HttpRequest,
-    loaded_data: web::Data<RwLock<LoadedData>>,
+    loaded_data: web::Data<LoadedDataStore>,
+    short_codes: web::Data<ShortCodeStore>,
 ) -> actix_web::Result<impl Responder> {
-    let loaded_data_guard = loaded_data.read().await;
-    let user_config = load_settings_cookie(&req, &loaded_data_guard.default_user_config);
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let user_config = load_user_config(&req, &loaded_data_guard.default_user_config)?;
+    let cookie_config = load_settings_cookie(&req, &loaded_data_guard.default_user_config);
 
+    let groups: BTreeMap<&str, &Vec<String>> = loaded_data_guard
+        .groups
+        .groups()
+        .map(|group| (group.name.as_str(), &group.services))
+        .collect();
+    let groups = serde_json::to_string(&groups).expect("failed to serialize groups");
+    let mut services: Vec<&str> = loaded_data_guard
+        .services
+        .keys()
+        .map(String::as_str)
+        .collect();
+    services.sort_unstable();
+    let services = serde_json::to_string(&services).expect("failed to serialize services");
+
+    let to_base64_json = |config: &UserConfig| {
+        BASE64_STANDARD.encode(serde_json::to_string(config).expect("failed to serialize config"))
+    };
     let template = ConfigureTemplate {
-        current_config: &user_config
-            .to_config_string()
-            .map_err(RedirectError::from)?,
+        current_config: &to_base64_json(&user_config),
+        default_config: &to_base64_json(&UserConfig::serde_default()),
+        cookie_error: cookie_config.as_ref().err().map(ToString::to_string),
+        groups: &groups,
+        services: &services,
+        short_codes_enabled: short_codes.is_enabled(),
     };
 
-    Ok(actix_web::HttpResponse::Ok()
+    let mut response = actix_web::HttpResponse::Ok();
+    // Rewrite cookie of older version in current format.
+    if let (Some(cookie), Ok(cookie_config)) = (req.cookie("config"), &cookie_config)
+        && config_string::version(cookie.value())
+            .is_ok_and(|version| version < config_string::CURRENT_VERSION)
+    {
+        response.cookie(config_cookie(cookie_config)?);
+    }
+    Ok(response
         .content_type("text/html; charset=utf-8")
         .body(template.render().expect("failed to render error page")))
 }
 
-#[get("/save")]
-async fn configure_save(req: HttpRequest) -> actix_web::Result<impl Responder> {
-    let query_string = req.query_string();
-    let user_config = UserConfig::from_config_string(query_string).map_err(RedirectError::from)?;
-    let cookie = Cookie::build(
-        "config",
-        user_config
+/// Generate random seed for sticky instance selection.
+fn generate_sticky_seed() -> String {
+    let bytes: [u8; 16] = rand::random();
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn config_cookie(user_config: &UserConfig) -> Result<Cookie<'static>, RedirectError> {
+    Ok(Cookie::build("config", user_config.to_config_string()?)
+        .path("/")
+        .expires(time::OffsetDateTime::now_utc() + time::Duration::days(9999))
+        .max_age(time::Duration::days(9999))
+        .finish())
+}
+
+/// Sticky seed of config in cookie.
+fn own_sticky_seed(req: &HttpRequest) -> Option<String> {
+    let cookie = req.cookie("config")?;
+    UserConfig::from_config_string(cookie.value())
+        .ok()?
+        .sticky_seed
+}
+
+/// Check that request comes from a page of this server.
+///
+/// Requests without `Origin` are allowed, browsers send it with every form
+/// submission.
+fn is_same_origin(req: &HttpRequest) -> bool {
+    let Some(origin) = req.headers().get(header::ORIGIN) else {
+        return true;
+    };
+    let origin_host = origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.split_once("://"))
+        .map(|(_, host)| host);
+    origin_host == Some(req.connection_info().host())
+}
+
+/// Save config to cookie, asking for confirmation first if it brings custom
+/// instances other than the ones already saved.
+///
+/// Configs are saved by plain links, so without confirmation a single click
+/// could send all redirects of the user to hosts chosen by someone else.
+fn save_shared_config(
+    req: &HttpRequest,
+    user_config: UserConfig,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let own_custom_instances = req
+        .cookie("config")
+        .and_then(|cookie| UserConfig::from_config_string(cookie.value()).ok())
+        .map(|config| config.custom_instances)
+        .unwrap_or_default();
+    if user_config.custom_instances.is_empty()
+        || user_config.custom_instances == own_custom_instances
+    {
+        return save_config(req, user_config);
+    }
+
+    let mut custom_instances: Vec<(&str, &url::Url)> = user_config
+        .custom_instances
+        .iter()
+        .flat_map(|(service, urls)| urls.iter().map(move |url| (service.as_str(), url)))
+        .collect();
+    custom_instances.sort_unstable();
+    let template = ConfirmConfigTemplate {
+        config: &user_config
             .to_config_string()
             .map_err(RedirectError::from)?,
-    )
-    .path("/")
-    .expires(time::OffsetDateTime::now_utc() + time::Duration::days(9999))
-    .max_age(time::Duration::days(9999))
-    .finish();
+        custom_instances: &custom_instances,
+    };
+    Ok(actix_web::HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(
+            template
+                .render()
+                .expect("failed to render config confirmation page"),
+        ))
+}
+
+/// Save config to cookie and redirect to settings page.
+///
+/// Seeds of shared configs are replaced by user's own seed, so users don't
+/// end up on the same sticky instances. Config without seed gets a new one.
+fn save_config(
+    req: &HttpRequest,
+    mut user_config: UserConfig,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let own_seed = own_sticky_seed(req);
+    user_config.sticky_seed = Some(match user_config.sticky_seed.take() {
+        Some(seed) if own_seed.as_ref() == Some(&seed) => seed,
+        Some(_) => own_seed.unwrap_or_else(generate_sticky_seed),
+        None => generate_sticky_seed(),
+    });
     Ok(actix_web::HttpResponse::TemporaryRedirect()
-        .cookie(cookie)
+        .cookie(config_cookie(&user_config)?)
         .insert_header((LOCATION, "/configure?success"))
         .finish())
 }
+
+#[get("/save")]
+async fn configure_save(req: HttpRequest) -> actix_web::Result<impl Responder> {
+    let query_string = req.query_string();
+    let user_config = UserConfig::from_config_string(query_string).map_err(RedirectError::from)?;
+    save_shared_config(&req, user_config)
+}
+
+#[derive(serde::Deserialize)]
+struct SaveForm {
+    config: String,
+}
+
+/// Save config submitted from configure or confirmation page.
+#[post("/save")]
+async fn configure_save_confirmed(
+    req: HttpRequest,
+    form: web::Form<SaveForm>,
+) -> actix_web::Result<impl Responder> {
+    if !is_same_origin(&req) {
+        return Err(RedirectError::CrossOrigin)?;
+    }
+    let user_config = UserConfig::from_config_string(&form.config).map_err(RedirectError::from)?;
+    save_config(&req, user_config)
+}
+
+/// Save config stored under short code to cookie.
+#[get("/c/{code}")]
+pub async fn apply_short_code(
+    req: HttpRequest,
+    code: web::Path<String>,
+    short_codes: web::Data<ShortCodeStore>,
+) -> actix_web::Result<impl Responder> {
+    let user_config = UserConfig {
+        sticky_seed: own_sticky_seed(&req),
+        ..short_codes.get(&code).map_err(RedirectError::from)?
+    };
+    save_shared_config(&req, user_config)
+}
@@ -1,9 +1,8 @@
 use std::collections::HashMap;
 
-use actix_web::{Responder, Scope, get, web};
+use actix_web::{HttpRequest, Responder, Scope, get, web};
 use askama::Template;
 use chrono::{DateTime, Utc};
-use tokio::sync::RwLock;
 
 use crate::{
     config::AppConfig,
@@ -11,11 +10,11 @@ use crate::{
     errors::RedirectError,
     filters,
     search::SearchError,
-    types::LoadedData,
+    types::LoadedDataStore,
 };
 use fastside_shared::serde_types::ServicesData;
 
-use super::{api, config, redirect};
+use super::{api, config, redirect, request_snapshot};
 
 pub fn scope(app_config: &AppConfig) -> Scope {
     web::scope("")
@@ -23,6 +22,7 @@ pub fn scope(app_config: &AppConfig) -> Scope {
         .service(favicon)
         .service(robots_txt)
         .service(config::scope(app_config))
+        .service(config::apply_short_code)
         .service(api::scope(app_config))
         .service(redirect::scope(app_config))
 }
@@ -39,14 +39,15 @@ pub struct IndexTemplate<'a> {
 
 #[get("/")]
 async fn index(
+    req: HttpRequest,
     crawler: web::Data<Crawler>,
-    loaded_data: web::Data<RwLock<LoadedData>>,
+    loaded_data: web::Data<LoadedDataStore>,
 ) -> actix_web::Result<impl Responder> {
     let data = crawler.read().await;
     let Some(crawled_services) = data.get_services() else {
         return Err(RedirectError::from(SearchError::CrawlerNotFetchedYet))?;
     };
-    let loaded_data_guard = loaded_data.read().await;
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
     let template = IndexTemplate {
         services: &loaded_data_guard.services,
         crawled_services: &crawled_services.services,
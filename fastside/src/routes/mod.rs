@@ -2,11 +2,116 @@ mod api;
 mod config;
 mod index;
 mod redirect;
+mod services;
 
-use actix_web::Scope;
+use std::sync::Arc;
 
-use crate::config::AppConfig;
+use actix_web::{
+    Error, HttpMessage, HttpRequest, Scope,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web,
+};
+
+use crate::{
+    config::AppConfig,
+    types::{LoadedData, LoadedDataStore},
+};
+
+/// Header with generation of services data used to handle request.
+const GENERATION_HEADER: HeaderName = HeaderName::from_static("x-fastside-generation");
+
+/// Generation of snapshot taken by handler, stored in request extensions.
+#[derive(Debug, Clone, Copy)]
+struct Generation(u64);
+
+/// Get current snapshot and record its generation for [`generation_header`].
+pub(super) async fn request_snapshot(
+    req: &HttpRequest,
+    loaded_data: &LoadedDataStore,
+) -> Arc<LoadedData> {
+    let snapshot = loaded_data.snapshot().await;
+    req.extensions_mut().insert(Generation(snapshot.generation));
+    snapshot
+}
 
 pub fn main_scope(config: &AppConfig) -> Scope {
     index::scope(config)
 }
+
+/// Middleware adding [`GENERATION_HEADER`] to every response, including
+/// redirects and pages.
+///
+/// Generation is the one of snapshot taken by handler with
+/// [`request_snapshot`]. Responses of handlers which do not use services data
+/// get current generation.
+pub async fn generation_header(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut res = next.call(req).await?;
+    let recorded = res.request().extensions().get::<Generation>().copied();
+    let generation = match (
+        recorded,
+        res.request().app_data::<web::Data<LoadedDataStore>>(),
+    ) {
+        (Some(Generation(generation)), _) => Some(generation),
+        (None, Some(loaded_data)) => Some(loaded_data.snapshot().await.generation),
+        (None, None) => None,
+    };
+    if let Some(generation) = generation {
+        res.headers_mut()
+            .insert(GENERATION_HEADER, HeaderValue::from(generation));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, middleware::from_fn, test};
+
+    use super::*;
+    use crate::types::LoadedData;
+
+    #[actix_web::test]
+    async fn test_generation_header() {
+        let loaded_data = web::Data::new(LoadedDataStore::new(LoadedData::empty()));
+        loaded_data.replace(LoadedData::empty()).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(generation_header))
+                .app_data(loaded_data)
+                .route("/", web::get().to(HttpResponse::TemporaryRedirect)),
+        )
+        .await;
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.headers().get(GENERATION_HEADER).unwrap(), "2");
+    }
+
+    #[actix_web::test]
+    async fn test_generation_header_uses_handler_snapshot() {
+        async fn handler(
+            req: HttpRequest,
+            loaded_data: web::Data<LoadedDataStore>,
+        ) -> HttpResponse {
+            request_snapshot(&req, &loaded_data).await;
+            // Reload lands after handler took its snapshot.
+            loaded_data.replace(LoadedData::empty()).await;
+            HttpResponse::Ok().finish()
+        }
+
+        let loaded_data = web::Data::new(LoadedDataStore::new(LoadedData::empty()));
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(generation_header))
+                .app_data(loaded_data.clone())
+                .route("/", web::get().to(handler)),
+        )
+        .await;
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.headers().get(GENERATION_HEADER).unwrap(), "1");
+        assert_eq!(loaded_data.snapshot().await.generation, 2);
+    }
+}
@@ -1,74 +1,167 @@
+use std::{borrow::Cow, sync::Arc};
+
 use actix_web::{
     HttpRequest, Responder, Scope, get,
     http::{Method, header::LOCATION},
     web,
 };
 use askama::Template;
-use tokio::sync::RwLock;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use tokio::sync::RwLockReadGuard;
 
 use crate::{
     config::AppConfig,
-    crawler::{CrawledService, Crawler},
+    crawler::{CrawledData, CrawledService, CrawledServices, Crawler, CustomInstances},
     errors::RedirectError,
+    filters,
+    geoip::{GeoIp, GeoLocation, client_location},
+    load::LoadTracker,
     search::{
-        SearchError, find_redirect_service_by_name, find_redirect_service_by_url,
-        get_redirect_instance, get_redirect_instances,
+        Replacement, SearchError, SelectContext, distance_order, find_group_redirect_instance,
+        find_original_url, find_redirect_service_by_instance_url, find_redirect_service_by_name,
+        find_redirect_service_by_url, get_fallback_instance, get_redirect_instance,
+        get_redirect_instances, latency_weight, order_group_services, reliability_order,
+        resolve_replacement, sort_least_loaded, sticky_score,
+    },
+    types::{LoadedData, LoadedDataStore},
+    utils::{
+        url::{compose_redirect_url, split_fragment},
+        user_config::{load_user_config, strip_config_param},
     },
-    types::{LoadedData, Regexes},
-    utils::user_config::load_settings_cookie,
 };
+
+use super::request_snapshot;
 use fastside_shared::{
     config::{SelectMethod, UserConfig},
+    matcher::CompiledGroup,
     serde_types::Service,
+    url_utils::join_instance_url,
 };
 
 pub fn scope(_config: &AppConfig) -> Scope {
     web::scope("")
         .service(history_redirect)
         .service(cached_redirect)
+        .service(original_redirect)
+        // Same routes with user config string in path instead of cookie.
+        .service(
+            web::scope("/~{config}")
+                .service(history_redirect)
+                .service(cached_redirect)
+                .route("/{path:.*}", web::get().to(base_redirect))
+                .route("/{path:.*}", web::post().to(base_redirect)),
+        )
+        // Same routes with config stored under short code.
+        .service(
+            web::scope("/c/{code}")
+                .service(history_redirect)
+                .service(cached_redirect)
+                .route("/{path:.*}", web::get().to(base_redirect))
+                .route("/{path:.*}", web::post().to(base_redirect)),
+        )
         .route("/{path:.*}", web::get().to(base_redirect))
         .route("/{path:.*}", web::post().to(base_redirect))
 }
 
+#[derive(Deserialize)]
+struct RedirectPath {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct CachedRedirectPath {
+    service_name: String,
+}
+
 #[derive(Template)]
-#[template(path = "cached_redirect.html", escape = "none")]
+#[template(path = "cached_redirect.html")]
 pub struct CachedRedirectTemplate<'a> {
     pub urls: Vec<&'a reqwest::Url>,
+    /// Selection weights of `urls` for [`SelectMethod::WeightedLatency`].
+    pub weights: Vec<f64>,
     pub select_method: &'a SelectMethod,
 }
 
 #[get("/@cached/{service_name}/{path:.*}")]
 async fn cached_redirect(
     req: HttpRequest,
-    path: web::Path<(String, String)>,
+    path: web::Path<CachedRedirectPath>,
     config: web::Data<AppConfig>,
     crawler: web::Data<Crawler>,
-    loaded_data: web::Data<RwLock<LoadedData>>,
+    loaded_data: web::Data<LoadedDataStore>,
+    geoip: web::Data<Option<Arc<GeoIp>>>,
+    load: web::Data<LoadTracker>,
 ) -> actix_web::Result<impl Responder> {
-    let (service_name, _) = path.into_inner();
+    let service_name = path.into_inner().service_name;
 
-    let loaded_data_guard = loaded_data.read().await;
-    let user_config = load_settings_cookie(&req, &loaded_data_guard.default_user_config);
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let user_config = load_user_config(&req, &loaded_data_guard.default_user_config)?;
+    let client = client_location(&req, geoip.get_ref().as_deref(), &config.geoip);
+    // Checked without locking crawled data, checks may take a while.
+    let service_name = {
+        let guard = crawler.read().await;
+        let (_, service) =
+            find_redirect_service_by_name(&guard, &loaded_data_guard.services, &service_name)
+                .await
+                .map_err(RedirectError::from)?;
+        service.name.clone()
+    };
+    let mut custom = crawler
+        .check_custom_instances(&loaded_data_guard, &user_config, &[&service_name])
+        .await;
 
     let guard = crawler.read().await;
-    let (crawled_service, _) =
+    let (crawled_service, service) =
         find_redirect_service_by_name(&guard, &loaded_data_guard.services, &service_name)
             .await
             .map_err(RedirectError::from)?;
-    let mut instances = get_redirect_instances(
-        crawled_service,
-        &user_config.required_tags,
-        &user_config.forbidden_tags,
-        &user_config.preferred_instances,
-    )
-    .ok_or(RedirectError::from(SearchError::NoInstancesFound))?;
-    if user_config.select_method == SelectMethod::LowPing {
-        instances.sort_by(|a, b| a.status.as_isize().cmp(&b.status.as_isize()));
+    let custom = custom.remove(&service.name).unwrap_or_default();
+    let user_config = user_config.for_service(service);
+    let mut instances = get_redirect_instances(crawled_service, &user_config, &custom)
+        .ok_or(RedirectError::from(SearchError::NoInstancesFound))?;
+    match (&user_config.select_method, &user_config.sticky_seed) {
+        (SelectMethod::LowPing, _) => instances.sort_by_key(|i| i.status.as_isize()),
+        (SelectMethod::Nearest, _) => match &client {
+            Some(client) => instances.sort_by(|a, b| distance_order(client, a, b)),
+            None => instances.sort_by_key(|i| i.status.as_isize()),
+        },
+        (SelectMethod::Sticky, Some(seed)) => {
+            instances.sort_by_key(|i| std::cmp::Reverse(sticky_score(seed, i.url.as_str())))
+        }
+        (SelectMethod::Sticky, None) => instances.shuffle(&mut rand::thread_rng()),
+        (SelectMethod::MostReliable, _) => instances.sort_by(|a, b| reliability_order(a, b)),
+        (SelectMethod::LeastLoaded, _) => sort_least_loaded(&load, &mut instances),
+        _ => {}
     }
     debug!("User config: {user_config:?}");
 
+    let weights: Vec<f64> = instances
+        .iter()
+        .map(|i| latency_weight(&i.status, user_config.latency_exponent))
+        .collect();
+
+    // Instance is picked by the page, so record expected share of redirect
+    // for random methods and the first instance otherwise.
+    let total_weight: f64 = weights.iter().sum();
+    for (i, (instance, weight)) in instances.iter().zip(&weights).enumerate() {
+        let share = match &user_config.select_method {
+            SelectMethod::Random => 1.0 / instances.len() as f64,
+            SelectMethod::WeightedLatency if total_weight > 0.0 => weight / total_weight,
+            SelectMethod::WeightedLatency => 1.0 / instances.len() as f64,
+            _ if i == 0 => 1.0,
+            _ => 0.0,
+        };
+        // Custom instances are chosen by users, counting them would let
+        // anyone grow the load counters without bound.
+        if share > 0.0 && !custom.iter().any(|c| c.url == instance.url) {
+            load.record(&instance.url, share);
+        }
+    }
+
     let template = CachedRedirectTemplate {
         urls: instances.iter().map(|i| &i.url).collect(),
+        weights,
         select_method: &user_config.select_method,
     };
 
@@ -84,6 +177,39 @@ async fn cached_redirect(
         .body(template.render().expect("failed to render error page")))
 }
 
+#[get("/@original/{path:.*}")]
+async fn original_redirect(
+    req: HttpRequest,
+    path: web::Path<String>,
+    loaded_data: web::Data<LoadedDataStore>,
+) -> actix_web::Result<impl Responder> {
+    let path = path.into_inner();
+    let (path, fragment) = split_fragment(&path);
+    let mut path = path.to_string();
+    let query = req.query_string();
+    if !query.is_empty() {
+        path.push('?');
+        path.push_str(query);
+    }
+
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let (_, mut url) = find_original_url(
+        &loaded_data_guard.instance_index,
+        &loaded_data_guard.reverse_matcher,
+        &path,
+    )
+    .map_err(RedirectError::from)?;
+    if let Some(fragment) = fragment {
+        url.set_fragment(Some(fragment));
+    }
+
+    debug!("Redirecting to original {url}");
+
+    Ok(actix_web::HttpResponse::TemporaryRedirect()
+        .insert_header((LOCATION, url.to_string()))
+        .finish())
+}
+
 #[derive(Template)]
 #[template(path = "history_redirect.html")]
 pub struct HistoryRedirectTemplate<'a> {
@@ -93,16 +219,26 @@ pub struct HistoryRedirectTemplate<'a> {
 #[get("/_/{path:.*}")]
 async fn history_redirect(
     req: HttpRequest,
-    path: web::Path<String>,
+    path: web::Path<RedirectPath>,
 ) -> actix_web::Result<impl Responder> {
-    let mut path = path.into_inner();
+    let path = path.into_inner().path;
+    let (path, fragment) = split_fragment(&path);
+    // Keep config embedded in path.
+    let mut path = match (req.match_info().get("config"), req.match_info().get("code")) {
+        (Some(config), _) => format!("/~{config}/{path}"),
+        (None, Some(code)) => format!("/c/{code}/{path}"),
+        (None, None) => format!("/{path}"),
+    };
     let query = req.query_string();
     if !query.is_empty() {
         path.push('?');
         path.push_str(query);
     }
+    if let Some(fragment) = fragment {
+        path.push('#');
+        path.push_str(fragment);
+    }
 
-    let path = format!("/{path}");
     let template = HistoryRedirectTemplate { path: &path };
 
     Ok(actix_web::HttpResponse::Ok()
@@ -117,89 +253,414 @@ pub struct FallbackRedirectTemplate<'a> {
     pub fallback: &'a str,
 }
 
-pub async fn find_redirect(
-    crawler: &Crawler,
-    loaded_data: &LoadedData,
-    regexes: &Regexes,
-    user_config: &UserConfig,
-    path: &str,
-) -> Result<(String, bool), RedirectError> {
-    let is_url_query = if path.starts_with("http://") || path.starts_with("https://") {
-        true
-    } else {
-        path[0..path.find('/').unwrap_or(0)].contains('.')
-    };
+#[derive(Template)]
+#[template(path = "replaced_redirect.html")]
+pub struct ReplacedRedirectTemplate<'a> {
+    pub url: &'a str,
+    pub replacement: &'a Replacement,
+}
 
-    let guard = crawler.read().await;
+/// Check if redirect path is URL rather than service name.
+///
+/// First segment is looked up in known domains and instance hosts, then in
+/// service names, aliases and groups. Unknown segments are URLs if they
+/// contain a dot.
+fn is_url_query(loaded_data: &LoadedData, path: &str) -> bool {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        return true;
+    }
+    let segment = path.split(['/', '?']).next().unwrap_or_default();
+    let host = segment.split(':').next().unwrap_or_default();
+    if loaded_data.domains.contains(host) || loaded_data.instance_index.contains_host(host) {
+        return true;
+    }
+    let is_service = loaded_data.services.contains_key(segment)
+        || loaded_data
+            .services
+            .values()
+            .any(|service| service.aliases.iter().any(|alias| alias == segment))
+        || loaded_data.groups.get(segment).is_some();
+    !is_service && host.contains('.')
+}
+
+/// Query of pasted URL `input` merged into redirect URL. Empty if regex
+/// matching the URL drops query.
+pub fn forwarded_query<'a>(loaded_data: &LoadedData, input: &str, query: &'a str) -> &'a str {
+    match loaded_data.matcher.find(input) {
+        Some((entry, _)) if entry.drop_query => "",
+        _ => query,
+    }
+}
+
+/// Redirect found for request.
+#[derive(Debug)]
+pub struct FoundRedirect {
+    pub url: String,
+    /// Name of service of the selected instance.
+    pub service: String,
+    /// Selected instance, or static fallback.
+    pub instance: reqwest::Url,
+    pub is_fallback: bool,
+    /// Whether selected instance is a custom instance from user config.
+    pub is_custom: bool,
+    /// Set if requested service is deprecated and was replaced.
+    pub replacement: Option<Replacement>,
+}
+
+impl FoundRedirect {
+    /// Count redirect to selected instance. Fallbacks and custom instances,
+    /// which anyone may make up, are not counted.
+    pub fn record(&self, load: &LoadTracker) {
+        if !self.is_fallback && !self.is_custom {
+            load.record(&self.instance, 1.0);
+        }
+    }
+}
+
+fn is_custom_instance(custom: &CustomInstances, url: &reqwest::Url) -> bool {
+    custom
+        .values()
+        .flatten()
+        .any(|instance| instance.url == *url)
+}
+
+/// Service or group found for redirect path.
+enum RedirectTarget<'a> {
+    Service {
+        crawled_service: &'a CrawledService,
+        service: &'a Service,
+        redir_path: String,
+        /// Whether service was explicitly requested rather than found by URL.
+        requested: bool,
+        /// Set if requested service is deprecated and was replaced.
+        replacement: Option<Replacement>,
+    },
+    Group {
+        group: &'a CompiledGroup,
+        redir_path: String,
+    },
+}
+
+impl RedirectTarget<'_> {
+    /// Names of services the redirect may go to.
+    fn service_names(&self, loaded_data: &LoadedData) -> Vec<String> {
+        let group = match self {
+            Self::Service { service, .. } => match loaded_data.groups.for_service(&service.name) {
+                Some(group) => group,
+                None => return vec![service.name.clone()],
+            },
+            Self::Group { group, .. } => group,
+        };
+        group.services.clone()
+    }
+}
+
+async fn find_redirect_target<'a>(
+    guard: &'a RwLockReadGuard<'a, CrawledData>,
+    loaded_data: &'a LoadedData,
+    path: &str,
+) -> Result<RedirectTarget<'a>, RedirectError> {
+    // Explicitly requested service is tried first among group services.
+    let mut requested = true;
     let (redir_path, crawled_service, service): (String, &CrawledService, &Service) =
-        match is_url_query {
+        match is_url_query(loaded_data, path) {
             true => {
-                let (crawled_service, service, redir_path) =
-                    find_redirect_service_by_url(&guard, &loaded_data.services, regexes, path)
+                // Links to known instances are redirected to a live instance of the same service.
+                let found = match find_redirect_service_by_instance_url(
+                    guard,
+                    &loaded_data.services,
+                    &loaded_data.instance_index,
+                    path,
+                )
+                .await
+                {
+                    Err(SearchError::ServiceNotFound) => {
+                        requested = false;
+                        find_redirect_service_by_url(
+                            guard,
+                            &loaded_data.services,
+                            &loaded_data.matcher,
+                            path,
+                        )
                         .await
-                        .map_err(RedirectError::from)?;
+                    }
+                    found => found,
+                };
+                let (crawled_service, service, redir_path) = found.map_err(RedirectError::from)?;
                 (redir_path, crawled_service, service)
             }
             false => {
                 let service_name = path.split('/').next().unwrap();
                 let redir_path = path[service_name.len()..].to_string();
-                let (crawled_service, service) =
-                    find_redirect_service_by_name(&guard, &loaded_data.services, service_name)
-                        .await
-                        .map_err(RedirectError::from)?;
-                (redir_path, crawled_service, service)
+                match find_redirect_service_by_name(guard, &loaded_data.services, service_name)
+                    .await
+                {
+                    Ok((crawled_service, service)) => (redir_path, crawled_service, service),
+                    Err(SearchError::ServiceNotFound) => {
+                        let group = loaded_data
+                            .groups
+                            .get(service_name)
+                            .ok_or(RedirectError::from(SearchError::ServiceNotFound))?;
+                        return Ok(RedirectTarget::Group { group, redir_path });
+                    }
+                    Err(err) => return Err(RedirectError::from(err)),
+                }
             }
         };
 
+    // Deprecated services are redirected to their replacements.
+    let (crawled_service, service, redir_path, replacement) = resolve_replacement(
+        guard,
+        &loaded_data.services,
+        &loaded_data.replacements,
+        (crawled_service, service, redir_path),
+    )
+    .await
+    .map_err(RedirectError::from)?;
+
+    Ok(RedirectTarget::Service {
+        crawled_service,
+        service,
+        redir_path,
+        requested,
+        replacement,
+    })
+}
+
+pub async fn find_redirect(
+    crawler: &Crawler,
+    loaded_data: &LoadedData,
+    user_config: &UserConfig,
+    client: Option<&GeoLocation>,
+    load: &LoadTracker,
+    path: &str,
+) -> Result<FoundRedirect, RedirectError> {
+    // Only custom instances of services the redirect may go to are checked.
+    // Checks may take a while, so crawled data is not locked meanwhile.
+    let custom = match user_config.custom_instances.is_empty() {
+        true => CustomInstances::new(),
+        false => {
+            let services = {
+                let guard = crawler.read().await;
+                find_redirect_target(&guard, loaded_data, path)
+                    .await?
+                    .service_names(loaded_data)
+            };
+            let services: Vec<&str> = services.iter().map(String::as_str).collect();
+            crawler
+                .check_custom_instances(loaded_data, user_config, &services)
+                .await
+        }
+    };
+    let ctx = SelectContext {
+        user_config,
+        client,
+        load,
+        custom: &custom,
+    };
+
+    let guard = crawler.read().await;
+    let (crawled_service, service, redir_path, requested, replacement) =
+        match find_redirect_target(&guard, loaded_data, path).await? {
+            RedirectTarget::Service {
+                crawled_service,
+                service,
+                redir_path,
+                requested,
+                replacement,
+            } => (crawled_service, service, redir_path, requested, replacement),
+            RedirectTarget::Group { group, redir_path } => {
+                let crawled = guard
+                    .get_services()
+                    .ok_or(RedirectError::from(SearchError::CrawlerNotFetchedYet))?;
+                return find_group_redirect(
+                    crawled,
+                    loaded_data,
+                    ctx,
+                    group,
+                    None,
+                    None,
+                    &redir_path,
+                );
+            }
+        };
+
+    if let Some(group) = loaded_data.groups.for_service(&service.name) {
+        // Original URL is used to rewrite path for other services of the group.
+        let original = match requested {
+            false => Some(path.to_string()),
+            true => loaded_data
+                .reverse_matcher
+                .rewrite(&service.name, &redir_path)
+                .and_then(Result::ok),
+        };
+        let crawled = guard
+            .get_services()
+            .ok_or(RedirectError::from(SearchError::CrawlerNotFetchedYet))?;
+        let found = find_group_redirect(
+            crawled,
+            loaded_data,
+            ctx,
+            group,
+            Some((service, requested)),
+            original.as_deref(),
+            &redir_path,
+        )?;
+        return Ok(FoundRedirect {
+            replacement,
+            ..found
+        });
+    }
+
     let (redirect_instance, is_fallback) =
-        get_redirect_instance(crawled_service, service, user_config)
-            .map_err(RedirectError::from)?;
+        get_redirect_instance(crawled_service, service, ctx).map_err(RedirectError::from)?;
 
-    let url = redirect_instance
-        .url
-        .clone()
-        .join(&redir_path)
+    let url = join_instance_url(&redirect_instance.url, &redir_path)
         .map_err(RedirectError::from)?
         .to_string();
 
-    Ok((url, is_fallback))
+    Ok(FoundRedirect {
+        url,
+        service: service.name.clone(),
+        is_custom: is_custom_instance(&custom, &redirect_instance.url),
+        instance: redirect_instance.url,
+        is_fallback,
+        replacement,
+    })
+}
+
+/// Redirect to the first service of the group that has instances matching
+/// user config.
+///
+/// `service` is the service found for the request and whether it was
+/// explicitly requested. Explicitly requested service is tried first,
+/// otherwise user preference for the group goes first.
+fn find_group_redirect(
+    crawled: &CrawledServices,
+    loaded_data: &LoadedData,
+    ctx: SelectContext,
+    group: &CompiledGroup,
+    service: Option<(&Service, bool)>,
+    original: Option<&str>,
+    redir_path: &str,
+) -> Result<FoundRedirect, RedirectError> {
+    let requested = service.and_then(|(service, requested)| requested.then_some(&*service.name));
+    let order = order_group_services(group, ctx.user_config, requested);
+    let service_name = service.map(|(service, _)| service.name.as_str());
+    let found = find_group_redirect_instance(
+        crawled,
+        &loaded_data.services,
+        group,
+        &order,
+        original,
+        (service_name.unwrap_or_default(), redir_path),
+        ctx,
+    )
+    .map_err(RedirectError::from)?;
+
+    let (service_name, redirect_instance, redir_path, is_fallback) = match found {
+        Some((name, instance, redir_path)) => (name.to_string(), instance, redir_path, false),
+        None => {
+            // Static fallback of the found service, or of the first group
+            // service that has one.
+            let fallback_service = service
+                .map(|(service, _)| service)
+                .or_else(|| {
+                    order
+                        .iter()
+                        .filter_map(|name| loaded_data.services.get(*name))
+                        .find(|service| service.fallback.is_some())
+                })
+                .ok_or(RedirectError::from(SearchError::NoInstancesFound))?;
+            let instance = get_fallback_instance(fallback_service).map_err(RedirectError::from)?;
+            (
+                fallback_service.name.clone(),
+                instance,
+                redir_path.to_string(),
+                true,
+            )
+        }
+    };
+
+    let url = join_instance_url(&redirect_instance.url, &redir_path)
+        .map_err(RedirectError::from)?
+        .to_string();
+
+    Ok(FoundRedirect {
+        url,
+        service: service_name,
+        is_custom: is_custom_instance(ctx.custom, &redirect_instance.url),
+        instance: redirect_instance.url,
+        is_fallback,
+        replacement: None,
+    })
 }
 
 async fn base_redirect(
     req: HttpRequest,
-    path: web::Path<String>,
+    path: web::Path<RedirectPath>,
+    config: web::Data<AppConfig>,
     crawler: web::Data<Crawler>,
-    loaded_data: web::Data<RwLock<LoadedData>>,
-    regexes: web::Data<Regexes>,
+    loaded_data: web::Data<LoadedDataStore>,
+    geoip: web::Data<Option<Arc<GeoIp>>>,
+    load: web::Data<LoadTracker>,
 ) -> actix_web::Result<impl Responder> {
-    let path = path.into_inner();
+    let path = path.into_inner().path;
 
-    let loaded_data_guard = loaded_data.read().await;
-    let user_config = load_settings_cookie(&req, &loaded_data_guard.default_user_config);
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let user_config = load_user_config(&req, &loaded_data_guard.default_user_config)?;
+    let client = client_location(&req, geoip.get_ref().as_deref(), &config.geoip);
 
-    let (mut url, is_fallback) = find_redirect(
+    let query = strip_config_param(req.query_string());
+    let (path, fragment) = split_fragment(&path);
+    let is_url = is_url_query(&loaded_data_guard, path);
+    // Pasted URLs are matched with their query, as in the API.
+    let input = match query.is_empty() || !is_url {
+        true => Cow::Borrowed(path),
+        false => {
+            let separator = if path.contains('?') { '&' } else { '?' };
+            Cow::Owned(format!("{path}{separator}{query}"))
+        }
+    };
+    let found = find_redirect(
         crawler.get_ref(),
         &loaded_data_guard,
-        regexes.get_ref(),
         &user_config,
-        &path,
+        client.as_ref(),
+        &load,
+        &input,
     )
     .await?;
+    found.record(&load);
+    let FoundRedirect {
+        url,
+        service,
+        is_fallback,
+        replacement,
+        ..
+    } = found;
+    let ignore_fallback_warning = match loaded_data_guard.services.get(&service) {
+        Some(service) => user_config.for_service(service).ignore_fallback_warning,
+        None => user_config.ignore_fallback_warning,
+    };
+    let query = match is_url {
+        true => forwarded_query(&loaded_data_guard, &input, &query),
+        false => &query,
+    };
+    let url = compose_redirect_url(&url, query, fragment, config.query_precedence)
+        .map_err(RedirectError::from)?
+        .to_string();
 
-    let query = req.query_string();
-    if !query.is_empty() {
-        url.push('?');
-        url.push_str(query);
-    }
-
-    debug!("Redirecting to {url}, is_fallback: {is_fallback}");
+    debug!("Redirecting to {url}, is_fallback: {is_fallback}, replacement: {replacement:?}");
 
     match (
         is_fallback,
-        user_config.ignore_fallback_warning,
+        ignore_fallback_warning,
+        replacement,
+        user_config.ignore_replacement_notice,
         req.method(),
     ) {
-        (true, false, &Method::GET) => {
+        (true, false, _, _, &Method::GET) => {
             let template = FallbackRedirectTemplate { fallback: &url };
             Ok(actix_web::HttpResponse::Ok()
                 .content_type("text/html; charset=utf-8")
@@ -210,8 +671,89 @@ async fn base_redirect(
                         .expect("failed to render fallback redirect page"),
                 ))
         }
+        (_, _, Some(replacement), false, &Method::GET) => {
+            let template = ReplacedRedirectTemplate {
+                url: &url,
+                replacement: &replacement,
+            };
+            Ok(actix_web::HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .insert_header(("refresh", format!("5; url={url}")))
+                .body(
+                    template
+                        .render()
+                        .expect("failed to render replaced redirect page"),
+                ))
+        }
         _ => Ok(actix_web::HttpResponse::TemporaryRedirect()
             .insert_header((LOCATION, url.to_string()))
             .finish()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fastside_shared::{config::QueryPrecedence, matcher::UrlMatcher, serde_types::StoredData};
+
+    use super::*;
+    use crate::utils::url::url_query;
+
+    #[test]
+    fn test_forwarded_query() {
+        let stored: StoredData = serde_json::from_str(
+            r#"{"services": [{"type": "invidious", "instances": [], "regexes": [
+                {"regex": "^(https?:\\/\\/)?youtu\\.be/(?<video>[^?#/]+)",
+                 "url": "/watch?v=?${video}${query.t:+&t=?${query.t}}", "drop_query": true},
+                {"regex": "^(https?:\\/\\/)?youtube\\.com(/.*)$", "url": "$2"}
+            ]}]}"#,
+        )
+        .unwrap();
+        let loaded_data = LoadedData {
+            matcher: UrlMatcher::new(&stored.services).unwrap(),
+            ..LoadedData::empty()
+        };
+        let redirect = |input: &str| {
+            let (_, path) = loaded_data.matcher.rewrite(input).unwrap().unwrap();
+            compose_redirect_url(
+                &format!("https://inv.example.org{path}"),
+                forwarded_query(&loaded_data, input, url_query(input)),
+                None,
+                QueryPrecedence::Request,
+            )
+            .unwrap()
+            .to_string()
+        };
+        // Tracking parameter `si` is not forwarded, timestamp only if present.
+        assert_eq!(
+            redirect("https://youtu.be/abc?si=x&t=42"),
+            "https://inv.example.org/watch?v=abc&t=42"
+        );
+        assert_eq!(
+            redirect("https://youtu.be/abc?si=x"),
+            "https://inv.example.org/watch?v=abc"
+        );
+        // Other regexes keep query of the URL.
+        assert_eq!(
+            redirect("youtube.com/feed?si=x"),
+            "https://inv.example.org/feed?si=x"
+        );
+    }
+
+    #[test]
+    fn test_cached_redirect_template_escapes_urls() {
+        let url = reqwest::Url::parse(r#"https://a.example.org/?q=\"</script>"#).unwrap();
+        let html = CachedRedirectTemplate {
+            urls: vec![&url],
+            weights: vec![1.0],
+            select_method: &SelectMethod::WeightedLatency,
+        }
+        .render()
+        .unwrap();
+        // Backslash kept by `Url` would otherwise escape the closing quote.
+        assert!(
+            html.contains(r#"let instances = ["https://a.example.org/?q=\\%22%3C/script%3E"];"#),
+            "{html}"
+        );
+        assert!(html.contains("let weights = [1.0];"), "{html}");
+    }
+}
@@ -0,0 +1,452 @@
+//! Read-only JSON API over services and their crawl results.
+//!
+//! Responses carry `ETag` and `Last-Modified` validators derived from the crawl
+//! time and services generation and its load time, so clients can poll with
+//! conditional requests and get `304 Not Modified` until the next crawl or
+//! services reload.
+
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, Responder, get,
+    http::header::{
+        CacheControl, CacheDirective, ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch,
+        LastModified,
+    },
+    web,
+};
+use chrono::{DateTime, Utc};
+use fastside_shared::{serde_types::Service, tag_filter::TagFilter};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    crawler::{CrawledInstance, CrawledService, CrawledServices, Crawler},
+    errors::{RedirectApiError, RedirectError},
+    geoip::GeoLocation,
+    search::{SearchError, find_redirect_service_by_name},
+    types::{LoadedData, LoadedDataStore},
+};
+
+use super::request_snapshot;
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Serialize)]
+struct ServiceInfo<'a> {
+    name: &'a str,
+    aliases: &'a [String],
+    fallback: Option<&'a Url>,
+    source_link: Option<&'a Url>,
+    deprecated_message: Option<&'a str>,
+    replaced_by: Option<&'a str>,
+    instance_count: usize,
+    alive_count: usize,
+}
+
+impl<'a> ServiceInfo<'a> {
+    fn new(service: &'a Service, crawled: Option<&CrawledService>) -> Self {
+        Self {
+            name: &service.name,
+            aliases: &service.aliases,
+            fallback: service.fallback.as_ref(),
+            source_link: service.source_link.as_ref(),
+            deprecated_message: service.deprecated_message.as_deref(),
+            replaced_by: service.replaced_by.as_deref(),
+            instance_count: crawled.map_or(0, |crawled| crawled.instances.len()),
+            alive_count: crawled.map_or(0, |crawled| crawled.get_alive_instances().count()),
+        }
+    }
+
+    fn is_deprecated(&self) -> bool {
+        self.deprecated_message.is_some() || self.replaced_by.is_some()
+    }
+}
+
+#[derive(Serialize)]
+struct InstanceInfo<'a> {
+    service: &'a str,
+    url: &'a Url,
+    tags: &'a [String],
+    alive: bool,
+    /// Response time of the last crawl in milliseconds.
+    latency_ms: Option<u128>,
+    /// Reason of the last crawl failure.
+    failure: Option<String>,
+    /// Percentage of successful crawls in history.
+    uptime: f64,
+    location: Option<&'a GeoLocation>,
+}
+
+impl<'a> InstanceInfo<'a> {
+    fn new(service: &'a str, instance: &'a CrawledInstance) -> Self {
+        let failure = instance.status.failure_reason();
+        Self {
+            service,
+            url: &instance.url,
+            tags: &instance.tags,
+            alive: failure.is_none(),
+            latency_ms: instance.status.latency().map(|d| d.as_millis()),
+            failure,
+            uptime: instance.uptime(),
+            location: instance.location.as_ref(),
+        }
+    }
+}
+
+/// Instances of the service, fastest first.
+fn sorted_instances(crawled: &CrawledService) -> Vec<&CrawledInstance> {
+    let mut instances: Vec<&CrawledInstance> = crawled.instances.iter().collect();
+    instances.sort_by(|a, b| {
+        (a.status.as_isize(), a.url.as_str()).cmp(&(b.status.as_isize(), b.url.as_str()))
+    });
+    instances
+}
+
+#[derive(Serialize)]
+struct Page<T> {
+    crawled_at: DateTime<Utc>,
+    generation: u64,
+    total: usize,
+    offset: usize,
+    limit: usize,
+    items: Vec<T>,
+}
+
+impl<T> Page<T> {
+    fn new(
+        crawled: &CrawledServices,
+        generation: u64,
+        items: impl Iterator<Item = T>,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Self {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        let items: Vec<T> = items.collect();
+        Self {
+            crawled_at: crawled.time,
+            generation,
+            total: items.len(),
+            offset,
+            limit,
+            items: items.into_iter().skip(offset).take(limit).collect(),
+        }
+    }
+}
+
+/// Time of the last crawl or services reload, whichever is later.
+fn modified_at(crawled: &CrawledServices, loaded_data: &LoadedData) -> DateTime<Utc> {
+    crawled.time.max(loaded_data.loaded_at)
+}
+
+/// Validators of crawl results, changing with every crawl and services reload.
+fn validators(crawled: &CrawledServices, loaded_data: &LoadedData) -> (EntityTag, HttpDate) {
+    let etag = EntityTag::new_strong(format!(
+        "{}-{}",
+        loaded_data.generation,
+        crawled.time.timestamp_millis()
+    ));
+    let last_modified = HttpDate::from(std::time::SystemTime::from(modified_at(
+        crawled,
+        loaded_data,
+    )));
+    (etag, last_modified)
+}
+
+/// Check if client already has the current response.
+///
+/// `If-None-Match` takes precedence over `If-Modified-Since`, as in RFC 9110.
+fn is_not_modified(req: &HttpRequest, etag: &EntityTag, modified: DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+        return match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        };
+    }
+    req.get_header::<IfModifiedSince>()
+        .is_some_and(|IfModifiedSince(since)| {
+            let since = DateTime::<Utc>::from(std::time::SystemTime::from(since));
+            modified.timestamp() <= since.timestamp()
+        })
+}
+
+/// Respond with JSON body or `304 Not Modified`, computing body only if needed.
+fn conditional_json<T: Serialize>(
+    req: &HttpRequest,
+    crawled: &CrawledServices,
+    loaded_data: &LoadedData,
+    body: impl FnOnce() -> T,
+) -> HttpResponse {
+    let (etag, last_modified) = validators(crawled, loaded_data);
+    let not_modified = is_not_modified(req, &etag, modified_at(crawled, loaded_data));
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header(ETag(etag))
+        .insert_header(LastModified(last_modified))
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]));
+    if not_modified {
+        response.finish()
+    } else {
+        response.json(body())
+    }
+}
+
+#[derive(Deserialize)]
+struct ServicesQuery {
+    /// Only deprecated services if `true`, only current ones if `false`.
+    deprecated: Option<bool>,
+    /// Only services with alive instances if `true`, without if `false`.
+    alive: Option<bool>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// List services with instance counts
+#[get("/services")]
+pub(super) async fn list_services(
+    req: HttpRequest,
+    query: web::Query<ServicesQuery>,
+    crawler: web::Data<Crawler>,
+    loaded_data: web::Data<LoadedDataStore>,
+) -> actix_web::Result<impl Responder> {
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let data = crawler.read().await;
+    let crawled = data
+        .get_services()
+        .ok_or(RedirectError::from(SearchError::CrawlerNotFetchedYet))
+        .map_err(RedirectApiError)?;
+    Ok(conditional_json(&req, crawled, &loaded_data_guard, || {
+        let mut services: Vec<&Service> = loaded_data_guard.services.values().collect();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        let services = services
+            .into_iter()
+            .map(|service| ServiceInfo::new(service, crawled.services.get(&service.name)))
+            .filter(|info| query.deprecated.is_none_or(|d| info.is_deprecated() == d))
+            .filter(|info| {
+                query
+                    .alive
+                    .is_none_or(|alive| (info.alive_count > 0) == alive)
+            });
+        Page::new(
+            crawled,
+            loaded_data_guard.generation,
+            services,
+            query.offset,
+            query.limit,
+        )
+    }))
+}
+
+#[derive(Serialize)]
+struct ServiceDetails<'a> {
+    crawled_at: DateTime<Utc>,
+    generation: u64,
+    #[serde(flatten)]
+    service: ServiceInfo<'a>,
+    instances: Vec<InstanceInfo<'a>>,
+}
+
+/// Get service by name or alias with all its instances
+#[get("/services/{name}")]
+pub(super) async fn get_service(
+    req: HttpRequest,
+    name: web::Path<String>,
+    crawler: web::Data<Crawler>,
+    loaded_data: web::Data<LoadedDataStore>,
+) -> actix_web::Result<impl Responder> {
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let guard = crawler.read().await;
+    let (crawled_service, service) =
+        find_redirect_service_by_name(&guard, &loaded_data_guard.services, &name)
+            .await
+            .map_err(RedirectError::from)
+            .map_err(RedirectApiError)?;
+    let crawled = guard
+        .get_services()
+        .ok_or(RedirectError::from(SearchError::CrawlerNotFetchedYet))
+        .map_err(RedirectApiError)?;
+    Ok(conditional_json(&req, crawled, &loaded_data_guard, || {
+        ServiceDetails {
+            crawled_at: crawled.time,
+            generation: loaded_data_guard.generation,
+            service: ServiceInfo::new(service, Some(crawled_service)),
+            instances: sorted_instances(crawled_service)
+                .into_iter()
+                .map(|instance| InstanceInfo::new(&service.name, instance))
+                .collect(),
+        }
+    }))
+}
+
+#[derive(Deserialize)]
+struct InstancesQuery {
+    /// Service name.
+    service: Option<String>,
+    /// Comma-separated tags, all of which instance must have.
+    tags: Option<String>,
+    /// Tag filter expression, as in user config.
+    tag_filter: Option<TagFilter>,
+    /// Only alive instances if `true`, only dead ones if `false`.
+    alive: Option<bool>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// List instances of all services, grouped by service and fastest first
+#[get("/instances")]
+pub(super) async fn list_instances(
+    req: HttpRequest,
+    query: web::Query<InstancesQuery>,
+    crawler: web::Data<Crawler>,
+    loaded_data: web::Data<LoadedDataStore>,
+) -> actix_web::Result<impl Responder> {
+    let loaded_data_guard = request_snapshot(&req, &loaded_data).await;
+    let data = crawler.read().await;
+    let crawled = data
+        .get_services()
+        .ok_or(RedirectError::from(SearchError::CrawlerNotFetchedYet))
+        .map_err(RedirectApiError)?;
+    Ok(conditional_json(&req, crawled, &loaded_data_guard, || {
+        let tags: Vec<&str> = query
+            .tags
+            .as_deref()
+            .map(|tags| tags.split(',').filter(|tag| !tag.is_empty()).collect())
+            .unwrap_or_default();
+        let mut services: Vec<&CrawledService> = crawled
+            .services
+            .values()
+            .filter(|service| {
+                query
+                    .service
+                    .as_ref()
+                    .is_none_or(|name| *name == service.name)
+            })
+            .collect();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        let instances = services
+            .into_iter()
+            .flat_map(|service| {
+                sorted_instances(service)
+                    .into_iter()
+                    .map(|instance| (service.name.as_str(), instance))
+            })
+            .filter(|(_, instance)| {
+                tags.iter()
+                    .all(|tag| instance.tags.iter().any(|t| t == tag))
+            })
+            .filter(|(_, instance)| {
+                query
+                    .tag_filter
+                    .as_ref()
+                    .is_none_or(|filter| filter.matches(&instance.tags))
+            })
+            .map(|(service, instance)| InstanceInfo::new(service, instance))
+            .filter(|info| query.alive.is_none_or(|alive| info.alive == alive));
+        Page::new(
+            crawled,
+            loaded_data_guard.generation,
+            instances,
+            query.offset,
+            query.limit,
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::{http::header, test::TestRequest};
+
+    use super::*;
+
+    #[test]
+    fn test_conditional_json() {
+        let crawled = CrawledServices {
+            services: HashMap::new(),
+            time: "2026-01-02T03:04:05.678Z".parse().unwrap(),
+        };
+        let loaded_data = LoadedData {
+            generation: 3,
+            loaded_at: "2026-01-01T00:00:00Z".parse().unwrap(),
+            ..LoadedData::empty()
+        };
+        let page = Page::new(&crawled, 3, 0..10, 8, Some(5));
+        assert_eq!((page.total, page.items), (10, vec![8, 9]));
+        let response = conditional_json(
+            &TestRequest::default().to_http_request(),
+            &crawled,
+            &loaded_data,
+            || Page::new(&crawled, 3, 0..10, 0, None),
+        );
+        assert_eq!(response.status(), 200);
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+        assert_eq!(etag, "\"3-1767323045678\"");
+        let last_modified = response
+            .headers()
+            .get(header::LAST_MODIFIED)
+            .unwrap()
+            .clone();
+        assert_eq!(last_modified, "Fri, 02 Jan 2026 03:04:05 GMT");
+
+        let status = |req: TestRequest| {
+            conditional_json(&req.to_http_request(), &crawled, &loaded_data, || ()).status()
+        };
+        assert_eq!(
+            status(TestRequest::default().insert_header((header::IF_NONE_MATCH, etag.clone()))),
+            304
+        );
+        assert_eq!(
+            status(TestRequest::default().insert_header((header::IF_NONE_MATCH, "\"2-1\""))),
+            200
+        );
+        assert_eq!(
+            status(
+                TestRequest::default()
+                    .insert_header((header::IF_MODIFIED_SINCE, last_modified.clone()))
+            ),
+            304
+        );
+        // ETag takes precedence over modification time.
+        assert_eq!(
+            status(
+                TestRequest::default()
+                    .insert_header((header::IF_NONE_MATCH, "\"2-1\""))
+                    .insert_header((header::IF_MODIFIED_SINCE, last_modified))
+            ),
+            200
+        );
+        assert_eq!(
+            status(
+                TestRequest::default()
+                    .insert_header((header::IF_MODIFIED_SINCE, "Fri, 02 Jan 2026 03:04:04 GMT"))
+            ),
+            200
+        );
+
+        // Services reload without a new crawl is a modification too.
+        let reloaded = LoadedData {
+            generation: 4,
+            loaded_at: "2026-01-03T00:00:00Z".parse().unwrap(),
+            ..LoadedData::empty()
+        };
+        let response = conditional_json(
+            &TestRequest::default()
+                .insert_header((header::IF_MODIFIED_SINCE, "Fri, 02 Jan 2026 03:04:05 GMT"))
+                .to_http_request(),
+            &crawled,
+            &reloaded,
+            || (),
+        );
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(header::LAST_MODIFIED).unwrap(),
+            "Sat, 03 Jan 2026 00:00:00 GMT"
+        );
+    }
+}
@@ -1,18 +1,25 @@
 use std::time::Duration;
 
-use regex::Captures;
 use tokio::sync::RwLockReadGuard;
 
 use crate::{
-    crawler::{CrawledData, CrawledInstance, CrawledInstanceStatus, CrawledService},
-    types::Regexes,
+    crawler::{
+        CrawledData, CrawledInstance, CrawledInstanceStatus, CrawledService, CrawledServices,
+        CustomInstances,
+    },
+    geoip::{GeoLocation, instance_distance},
+    load::LoadTracker,
 };
 use fastside_shared::{
     config::{SelectMethod, UserConfig},
+    matcher::{CompiledGroup, InstanceIndex, ReplacementMatcher, ReverseMatcher, UrlMatcher},
     serde_types::{Service, ServicesData},
+    url_template::ReplaceArgsError,
 };
 use rand::seq::SliceRandom;
+use serde::Serialize;
 use thiserror::Error;
+use url::Url;
 
 #[derive(Error, Debug)]
 pub enum SearchError {
@@ -22,6 +29,10 @@ pub enum SearchError {
     ServiceNotFound,
     #[error("no instances found")]
     NoInstancesFound,
+    #[error("original URL not found")]
+    OriginalNotFound,
+    #[error("replacement of service `{0}` not found")]
+    ReplacementNotFound(String),
     #[error("replace args error: `{0}`")]
     ReplaceArgsError(#[from] ReplaceArgsError),
 }
@@ -37,153 +48,193 @@ pub async fn find_redirect_service_by_name<'a>(
     };
 
     // Search for the service by name.
-    if data.services.contains_key(query) {
-        return Ok((&data.services[query], &services[query]));
+    let found_service = match services.get(query) {
+        Some(service) => Some(service),
+        // Search for the service by aliases.
+        None => {
+            let query_string = query.to_string();
+            services
+                .values()
+                .find(|service| service.aliases.contains(&query_string))
+        }
+    };
+    let Some(service) = found_service else {
+        return Err(SearchError::ServiceNotFound);
     };
 
-    // Search for the service by aliases.
-    let query_string = query.to_string();
-    let found_service: Option<&Service> = services
-        .values()
-        .find(|service| service.aliases.contains(&query_string));
-    if let Some(service) = found_service {
-        return Ok((&data.services[&service.name], service));
-    }
-
-    Err(SearchError::ServiceNotFound)
+    Ok((get_crawled_service(data, service)?, service))
 }
 
-#[derive(Error, Debug)]
-pub enum ReplaceArgsError {
-    #[error("invalid capture group number")]
-    InvalidCaptureGroup,
-    #[error("parse int error")]
-    ParseIntError(#[from] std::num::ParseIntError),
+/// Get crawled data for the service.
+///
+/// Crawled data may lag behind freshly reloaded services, so the service may
+/// not be crawled yet.
+fn get_crawled_service<'a>(
+    data: &'a CrawledServices,
+    service: &Service,
+) -> Result<&'a CrawledService, SearchError> {
+    data.services
+        .get(&service.name)
+        .ok_or(SearchError::CrawlerNotFetchedYet)
 }
 
-fn push_capture(
-    captures: &Captures,
-    num: usize,
-    is_encoded: bool,
-    out: &mut String,
-) -> Result<(), ReplaceArgsError> {
-    let arg = captures
-        .get(num)
-        .ok_or(ReplaceArgsError::InvalidCaptureGroup)?
-        .as_str();
-    let arg = if is_encoded {
-        urlencoding::encode(arg).to_string()
-    } else {
-        arg.to_string()
+pub async fn find_redirect_service_by_url<'a>(
+    guard: &'a RwLockReadGuard<'a, CrawledData>,
+    services: &'a ServicesData,
+    matcher: &'a UrlMatcher,
+    query: &str,
+) -> Result<(&'a CrawledService, &'a Service, String), SearchError> {
+    let data = match guard.get_services() {
+        Some(data) => data,
+        None => return Err(SearchError::CrawlerNotFetchedYet),
     };
-    out.push_str(&arg);
-    Ok(())
+    // Search for the service by regexes.
+    let Some(rewritten) = matcher.rewrite(query) else {
+        return Err(SearchError::ServiceNotFound);
+    };
+    let (entry, url) = rewritten?;
+    let service = services
+        .get(&entry.service_name)
+        .ok_or(SearchError::ServiceNotFound)?;
+    Ok((get_crawled_service(data, service)?, service, url))
 }
 
-fn replace_args_in_url(url: &str, captures: Captures) -> Result<String, ReplaceArgsError> {
-    let mut out = String::with_capacity(url.len());
-    let mut is_encoded = false;
-    let mut is_arg = false;
-    let mut escape = false;
-    let mut num = String::new();
-    for c in url.chars() {
-        match (c, is_arg, is_encoded, escape) {
-            ('\\', false, _, false) => {
-                debug!("Found '\\' in URL, escaping next character");
-                escape = true;
-            }
-            ('?', false, false, false) => {
-                debug!("Found '?' in URL");
-                is_encoded = true;
-            }
-            ('$', false, _, false) => {
-                debug!("Found '$' in URL");
-                is_arg = true;
-            }
-            ('0'..='9', true, _, false) => {
-                debug!("Found digit {c} in URL");
-                num.push(c);
-            }
-            (c, true, _, false) if num.is_empty() => {
-                debug!("Found non-digit {c} in URL while parsing arg");
-                is_arg = false;
-                is_encoded = false;
-                out.push('$');
-                out.push(c);
-            }
-            (c, false, true, false) => {
-                debug!("Found non-dollar {c} in URL while expecting arg");
-                is_encoded = false;
-                out.push('?');
-                out.push(c);
-            }
-            (c, true, _, false) => {
-                debug!("Found non-digit {c} in URL while parsing num, adding capture");
-                push_capture(&captures, num.parse()?, is_encoded, &mut out)?;
-                is_arg = false;
-                is_encoded = false;
-                num.clear();
-                out.push(c);
-            }
-            _ => {
-                debug!("Found non-dollar {c} in URL while not expecting arg");
-                out.push(c);
-                if escape {
-                    debug!("Disabling escape");
-                    escape = false;
-                }
-            }
+/// Deprecated service replaced during redirect search.
+#[derive(Debug, Clone, Serialize)]
+pub struct Replacement {
+    /// Name of deprecated service.
+    pub service: String,
+    /// Name of service it was replaced with.
+    pub replaced_by: String,
+    pub deprecated_message: Option<String>,
+}
+
+/// Follow `replaced_by` chain of the service.
+///
+/// Path is rewritten by `replacement_regexes` of every replaced service, or
+/// kept as is if none match. Returns final service, path on it and
+/// replacement info if service was replaced.
+pub async fn resolve_replacement<'a>(
+    guard: &'a RwLockReadGuard<'a, CrawledData>,
+    services: &'a ServicesData,
+    replacements: &ReplacementMatcher,
+    found: (&'a CrawledService, &'a Service, String),
+) -> Result<(&'a CrawledService, &'a Service, String, Option<Replacement>), SearchError> {
+    let (mut crawled_service, original_service, mut path) = found;
+    let mut service = original_service;
+    let mut visited = vec![service.name.as_str()];
+    while let Some(replaced_by) = &service.replaced_by {
+        if visited.contains(&replaced_by.as_str()) {
+            return Err(SearchError::ReplacementNotFound(
+                original_service.name.clone(),
+            ));
+        }
+        if let Some(rewritten) = replacements.rewrite(&service.name, &path) {
+            path = rewritten?;
         }
+        (crawled_service, service) = find_redirect_service_by_name(guard, services, replaced_by)
+            .await
+            .map_err(|err| match err {
+                SearchError::ServiceNotFound => {
+                    SearchError::ReplacementNotFound(original_service.name.clone())
+                }
+                err => err,
+            })?;
+        visited.push(&service.name);
     }
-    if is_arg {
-        debug!("Found EOF while parsing arg, adding capture");
-        push_capture(&captures, num.parse()?, is_encoded, &mut out)?;
+    let replacement = (service.name != original_service.name).then(|| Replacement {
+        service: original_service.name.clone(),
+        replaced_by: service.name.clone(),
+        deprecated_message: original_service.deprecated_message.clone(),
+    });
+    Ok((crawled_service, service, path, replacement))
+}
+
+/// Parse URL pasted by user. Scheme is optional.
+fn parse_query_url(query: &str) -> Option<Url> {
+    if query.starts_with("http://") || query.starts_with("https://") {
+        Url::parse(query).ok()
+    } else {
+        Url::parse(&format!("https://{query}")).ok()
     }
-    Ok(out)
 }
 
-pub async fn find_redirect_service_by_url<'a>(
+/// Find service by URL pointing to one of its known instances.
+///
+/// Used to rescue links to dead instances: path and query are kept relative
+/// to the instance base path.
+pub async fn find_redirect_service_by_instance_url<'a>(
     guard: &'a RwLockReadGuard<'a, CrawledData>,
     services: &'a ServicesData,
-    regexes: &'a Regexes,
+    instance_index: &'a InstanceIndex,
     query: &str,
 ) -> Result<(&'a CrawledService, &'a Service, String), SearchError> {
     let data = match guard.get_services() {
         Some(data) => data,
         None => return Err(SearchError::CrawlerNotFetchedYet),
     };
-    // Search for the service by regexes.
-    for (service_name, service) in services.iter() {
-        if let Some(service_regexes) = regexes.get(service_name) {
-            for service_regex in service_regexes {
-                let regex = &service_regex.regex;
-                let captures = regex.captures(query);
-                if let Some(captures) = captures {
-                    let url = service_regex.url.clone();
-                    let url = replace_args_in_url(&url, captures)?;
-                    return Ok((&data.services[service_name], service, url));
-                }
-            }
-        }
-    }
+    let url = parse_query_url(query).ok_or(SearchError::ServiceNotFound)?;
+    let (service_name, path) = instance_index
+        .find(&url)
+        .ok_or(SearchError::ServiceNotFound)?;
+    let service = services
+        .get(service_name)
+        .ok_or(SearchError::ServiceNotFound)?;
+    Ok((get_crawled_service(data, service)?, service, path))
+}
 
-    Err(SearchError::ServiceNotFound)
+/// Find original URL for URL on one of known instances.
+///
+/// Returns service name and original URL.
+pub fn find_original_url<'a>(
+    instance_index: &'a InstanceIndex,
+    reverse_matcher: &ReverseMatcher,
+    query: &str,
+) -> Result<(&'a str, Url), SearchError> {
+    let url = parse_query_url(query).ok_or(SearchError::ServiceNotFound)?;
+    let (service_name, path) = instance_index
+        .find(&url)
+        .ok_or(SearchError::ServiceNotFound)?;
+    let original = reverse_matcher
+        .rewrite(service_name, &path)
+        .ok_or(SearchError::OriginalNotFound)??;
+    let original = Url::parse(&original).map_err(|_| SearchError::OriginalNotFound)?;
+    Ok((service_name, original))
 }
 
+/// Get instances of the service matching user config.
+///
+/// `custom` are checked alive custom instances of the service. They are
+/// added by the user, so uptime and tags are not checked for them.
 pub fn get_redirect_instances<'a>(
     crawled_service: &'a CrawledService,
-    required_tags: &[String],
-    forbidden_tags: &[String],
-    preferred_instances: &[String],
+    user_config: &UserConfig,
+    custom: &'a [CrawledInstance],
 ) -> Option<Vec<&'a CrawledInstance>> {
     let alive_instances = crawled_service.get_alive_instances();
+    let tag_filter = user_config.tag_filter.as_ref();
     let instances = alive_instances
-        .filter(|i| required_tags.iter().all(|tag| i.tags.contains(tag)))
-        .filter(|i| forbidden_tags.iter().all(|tag| !i.tags.contains(tag)))
+        .filter(|i| i.uptime() >= user_config.min_uptime)
+        .filter(|i| {
+            user_config
+                .required_tags
+                .iter()
+                .all(|tag| i.tags.contains(tag))
+        })
+        .filter(|i| {
+            user_config
+                .forbidden_tags
+                .iter()
+                .all(|tag| !i.tags.contains(tag))
+        })
+        .filter(|i| tag_filter.is_none_or(|filter| filter.matches(&i.tags)))
+        .chain(custom)
+        .filter(|i| !user_config.is_blocked(&i.url))
         .collect::<Vec<_>>();
     if instances.is_empty() {
         return None;
     }
+    let preferred_instances = &user_config.preferred_instances;
     let instances = if preferred_instances.is_empty() {
         instances
     } else {
@@ -208,114 +259,413 @@ pub fn get_redirect_instances<'a>(
 
 const MAX_DURATION: Duration = Duration::from_secs(u64::MAX);
 
+/// Selection weight of instance for [`SelectMethod::WeightedLatency`].
+///
+/// Latency below 1 ms is treated as 1 ms.
+pub fn latency_weight(status: &CrawledInstanceStatus, exponent: f64) -> f64 {
+    match status {
+        CrawledInstanceStatus::Ok(ping) => {
+            let millis = (ping.as_secs_f64() * 1000.0).max(1.0);
+            1.0 / millis.powf(exponent)
+        }
+        _ => 0.0,
+    }
+}
+
+/// Rendezvous hashing score of instance for the seed.
+///
+/// Instance with the highest score is selected, so removing an instance only
+/// moves users whose instance was removed. Uses FNV-1a with a SplitMix64
+/// finalizer, which is stable across builds and platforms.
+pub fn sticky_score(seed: &str, instance_url: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in seed.bytes().chain([0]).chain(instance_url.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Order instances by uptime descending, then by latency ascending.
+pub fn reliability_order(a: &CrawledInstance, b: &CrawledInstance) -> std::cmp::Ordering {
+    b.uptime()
+        .total_cmp(&a.uptime())
+        .then_with(|| a.status.as_isize().cmp(&b.status.as_isize()))
+}
+
+/// Order instances by distance to client ascending, then by latency ascending.
+pub fn distance_order(
+    client: &GeoLocation,
+    a: &CrawledInstance,
+    b: &CrawledInstance,
+) -> std::cmp::Ordering {
+    instance_distance(client, a.location.as_ref())
+        .total_cmp(&instance_distance(client, b.location.as_ref()))
+        .then_with(|| a.status.as_isize().cmp(&b.status.as_isize()))
+}
+
+/// Recent redirects to each instance divided by its weight.
+pub fn relative_loads(load: &LoadTracker, instances: &[&CrawledInstance]) -> Vec<f64> {
+    load.loads(instances.iter().map(|i| &i.url))
+        .into_iter()
+        .zip(instances)
+        .map(|(load, i)| load / i.weight.max(1) as f64)
+        .collect()
+}
+
+/// Order instances `a` and `b` by relative load ascending, then by latency
+/// ascending.
+fn load_order(
+    instances: &[&CrawledInstance],
+    loads: &[f64],
+    a: usize,
+    b: usize,
+) -> std::cmp::Ordering {
+    loads[a].total_cmp(&loads[b]).then_with(|| {
+        instances[a]
+            .status
+            .as_isize()
+            .cmp(&instances[b].status.as_isize())
+    })
+}
+
+/// Pick the less loaded of two random instances.
+///
+/// Always picking the least loaded instance would send every concurrent
+/// request to it until its counter catches up.
+pub fn pick_least_loaded(instances: &[&CrawledInstance], loads: &[f64]) -> usize {
+    if instances.len() < 2 {
+        return 0;
+    }
+    let picked = rand::seq::index::sample(&mut rand::thread_rng(), instances.len(), 2);
+    let (a, b) = (picked.index(0), picked.index(1));
+    match load_order(instances, loads, a, b) {
+        std::cmp::Ordering::Greater => b,
+        _ => a,
+    }
+}
+
+/// Sort instances by relative load, then move the one picked by
+/// [`pick_least_loaded`] to the front.
+pub fn sort_least_loaded(load: &LoadTracker, instances: &mut [&CrawledInstance]) {
+    let loads = relative_loads(load, instances);
+    let first = pick_least_loaded(instances, &loads);
+    let mut order: Vec<usize> = (0..instances.len()).collect();
+    order.sort_by(|&a, &b| load_order(instances, &loads, a, b));
+    order.retain(|&i| i != first);
+    order.insert(0, first);
+    let sorted: Vec<&CrawledInstance> = order.iter().map(|&i| instances[i]).collect();
+    instances.copy_from_slice(&sorted);
+}
+
+/// Inputs of instance selection besides the service itself.
+#[derive(Clone, Copy)]
+pub struct SelectContext<'a> {
+    pub user_config: &'a UserConfig,
+    /// Client location, if known.
+    pub client: Option<&'a GeoLocation>,
+    /// Recent redirects per instance.
+    pub load: &'a LoadTracker,
+    /// Checked custom instances from user config.
+    pub custom: &'a CustomInstances,
+}
+
+/// Select instance of the service matching user config.
+///
+/// User overrides for the service are merged over global settings.
+///
+/// Returns `None` if no alive instance matches user tags.
+pub fn select_redirect_instance(
+    crawled_service: &CrawledService,
+    service: &Service,
+    ctx: SelectContext,
+) -> Option<CrawledInstance> {
+    let user_config = ctx.user_config.for_service(service);
+    let custom = ctx
+        .custom
+        .get(&service.name)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let instances = get_redirect_instances(crawled_service, &user_config, custom)?;
+    let lowest_ping = || {
+        instances
+            .iter()
+            .min_by_key(|i| match i.status {
+                CrawledInstanceStatus::Ok(ping) => ping,
+                _ => MAX_DURATION,
+            })
+            .unwrap()
+    };
+    let instance = match &user_config.select_method {
+        SelectMethod::Random => instances.choose(&mut rand::thread_rng()).unwrap(),
+        SelectMethod::LowPing => lowest_ping(),
+        SelectMethod::WeightedLatency => instances
+            .choose_weighted(&mut rand::thread_rng(), |i| {
+                latency_weight(&i.status, user_config.latency_exponent)
+            })
+            // All weights are zero or invalid, fall back to uniform selection.
+            .unwrap_or_else(|_| instances.choose(&mut rand::thread_rng()).unwrap()),
+        SelectMethod::Sticky => match &user_config.sticky_seed {
+            Some(seed) => instances
+                .iter()
+                .max_by_key(|i| sticky_score(seed, i.url.as_str()))
+                .unwrap(),
+            // No seed to stick to, e.g. in API requests.
+            None => instances.choose(&mut rand::thread_rng()).unwrap(),
+        },
+        SelectMethod::MostReliable => instances
+            .iter()
+            .min_by(|a, b| reliability_order(a, b))
+            .unwrap(),
+        SelectMethod::Nearest => match ctx.client {
+            Some(client) => instances
+                .iter()
+                .min_by(|a, b| distance_order(client, a, b))
+                .unwrap(),
+            // Client location is unknown, e.g. without GeoIP database.
+            None => lowest_ping(),
+        },
+        SelectMethod::LeastLoaded => {
+            let loads = relative_loads(ctx.load, &instances);
+            instances[pick_least_loaded(&instances, &loads)]
+        }
+    };
+    Some(instance.to_owned().to_owned())
+}
+
+/// Get static fallback of the service as instance.
+pub fn get_fallback_instance(service: &Service) -> Result<CrawledInstance, SearchError> {
+    match &service.fallback {
+        Some(fallback) => Ok(CrawledInstance {
+            url: fallback.clone(),
+            status: CrawledInstanceStatus::Ok(MAX_DURATION),
+            tags: vec![],
+            history: Default::default(),
+            location: None,
+            weight: 1,
+        }),
+        None => Err(SearchError::NoInstancesFound),
+    }
+}
+
 pub fn get_redirect_instance(
     crawled_service: &CrawledService,
     service: &Service,
-    user_config: &UserConfig,
+    ctx: SelectContext,
 ) -> Result<(CrawledInstance, bool), SearchError> {
-    let instances = get_redirect_instances(
-        crawled_service,
-        &user_config.required_tags,
-        &user_config.forbidden_tags,
-        &user_config.preferred_instances,
-    );
-    match &instances {
-        None => match &service.fallback {
-            Some(fallback) => Ok((
-                CrawledInstance {
-                    url: fallback.clone(),
-                    status: CrawledInstanceStatus::Ok(MAX_DURATION),
-                    tags: vec![],
-                },
-                true,
-            )),
-            None => Err(SearchError::NoInstancesFound),
-        },
-        Some(instances) => Ok((
-            match &user_config.select_method {
-                SelectMethod::Random => instances
-                    .choose(&mut rand::thread_rng())
-                    .unwrap()
-                    .to_owned()
-                    .to_owned(),
-                SelectMethod::LowPing => instances
-                    .iter()
-                    .min_by_key(|i| match i.status {
-                        CrawledInstanceStatus::Ok(ping) => ping,
-                        _ => MAX_DURATION,
-                    })
-                    .unwrap()
-                    .to_owned()
-                    .to_owned(),
+    match select_redirect_instance(crawled_service, service, ctx) {
+        Some(instance) => Ok((instance, false)),
+        None => Ok((get_fallback_instance(service)?, true)),
+    }
+}
+
+/// Order group services for redirect.
+///
+/// `requested` service goes first, then services in order of user preference
+/// for this group, then the rest in group order.
+pub fn order_group_services<'a>(
+    group: &'a CompiledGroup,
+    user_config: &'a UserConfig,
+    requested: Option<&'a str>,
+) -> Vec<&'a str> {
+    let preferences = user_config
+        .group_preferences
+        .get(&group.name)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut ordered: Vec<&str> = Vec::with_capacity(group.services.len());
+    let candidates = requested
+        .into_iter()
+        .chain(preferences.iter().map(String::as_str))
+        .chain(group.services.iter().map(String::as_str));
+    for name in candidates {
+        if group.services.iter().any(|s| s == name) && !ordered.contains(&name) {
+            ordered.push(name);
+        }
+    }
+    ordered
+}
+
+/// Find instance of the first group service that has instances matching user
+/// config.
+///
+/// Path for each service is rewritten from `original` URL using group
+/// rewrites. If original URL is unknown, `path` is reused as is. Services
+/// whose rewrites do not match original URL are skipped.
+///
+/// Returns service name, instance and path on the instance.
+pub fn find_group_redirect_instance<'a>(
+    crawled: &CrawledServices,
+    services: &ServicesData,
+    group: &CompiledGroup,
+    order: &[&'a str],
+    original: Option<&str>,
+    path: (&str, &str),
+    ctx: SelectContext,
+) -> Result<Option<(&'a str, CrawledInstance, String)>, SearchError> {
+    let (path_service, path) = path;
+    for name in order {
+        let service_path = match original {
+            _ if *name == path_service => path.to_string(),
+            None => path.to_string(),
+            Some(original) => match group.rewrite(name, original) {
+                Some(rewritten) => rewritten?,
+                None => continue,
             },
-            false,
-        )),
+        };
+        let (Some(crawled_service), Some(service)) =
+            (crawled.services.get(*name), services.get(*name))
+        else {
+            continue;
+        };
+        if let Some(instance) = select_redirect_instance(crawled_service, service, ctx) {
+            return Ok(Some((name, instance, service_path)));
+        }
     }
+    Ok(None)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use regex::{Captures, Regex};
-
-    fn setup_captures<'t>(text: &'t str, re: &'t str) -> Captures<'t> {
-        let regex = Regex::new(re).unwrap();
-        regex.captures(text).unwrap()
-    }
+    use fastside_shared::{matcher::GroupIndex, serde_types::StoredData};
 
     #[test]
-    fn test_no_placeholders() {
-        let url = "http://example.com/path";
-        let captures = setup_captures("input text", r"(input) (text)");
-        let result = replace_args_in_url(url, captures);
-        assert_eq!(result.unwrap(), "http://example.com/path");
-    }
+    fn test_order_group_services() {
+        let stored: StoredData = serde_json::from_str(
+            r#"{"services": [
+                {"type": "a", "instances": []},
+                {"type": "b", "instances": []},
+                {"type": "c", "instances": []}
+            ], "groups": [
+                {"name": "g", "services": [{"service": "a"}, {"service": "b"}, {"service": "c"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let index = GroupIndex::new(&stored.groups, &stored.services).unwrap();
+        let group = index.get("g").unwrap();
+        let mut user_config = UserConfig::default();
+        user_config.group_preferences.insert(
+            "g".to_string(),
+            vec!["c".to_string(), "unknown".to_string()],
+        );
 
-    #[test]
-    fn test_simple_replacement() {
-        let url = "http://example.com/$1";
-        let captures = setup_captures("value", r"(value)");
-        let result = replace_args_in_url(url, captures);
-        assert_eq!(result.unwrap(), "http://example.com/value");
+        assert_eq!(
+            order_group_services(group, &user_config, None),
+            vec!["c", "a", "b"]
+        );
+        assert_eq!(
+            order_group_services(group, &user_config, Some("b")),
+            vec!["b", "c", "a"]
+        );
     }
 
     #[test]
-    fn test_url_encoding() {
-        let url = r"http://example.com/\?param=?$1";
-        let captures = setup_captures("value space", r"(value space)");
-        let result = replace_args_in_url(url, captures);
-        assert_eq!(result.unwrap(), "http://example.com/?param=value%20space");
-    }
+    fn test_least_loaded() {
+        let instance = |url: &str, weight| CrawledInstance {
+            url: Url::parse(url).unwrap(),
+            status: CrawledInstanceStatus::Ok(Duration::from_millis(100)),
+            tags: vec![],
+            history: Default::default(),
+            location: None,
+            weight,
+        };
+        let instances = [
+            instance("https://a.example/", 1),
+            instance("https://b.example/", 2),
+            instance("https://c.example/", 1),
+        ];
+        let instances: Vec<&CrawledInstance> = instances.iter().collect();
+        let load = LoadTracker::new(Duration::from_secs(60));
+        load.record(&instances[0].url, 10.0);
+        load.record(&instances[1].url, 10.0);
+        // Loads decay a bit while test runs.
+        let loads = relative_loads(&load, &instances);
+        let rounded: Vec<f64> = loads.iter().map(|load| load.round()).collect();
+        assert_eq!(rounded, vec![10.0, 5.0, 0.0]);
 
-    #[test]
-    fn test_multiple_replacements() {
-        let url = r"http://example.com/$1/page\?$2";
-        let captures = setup_captures("value1 value2", r"(value1) (value2)");
-        let result = replace_args_in_url(url, captures);
-        assert_eq!(result.unwrap(), "http://example.com/value1/page?value2");
+        // The most loaded instance is never picked, the others share requests.
+        let mut picked = [0; 3];
+        for _ in 0..200 {
+            picked[pick_least_loaded(&instances, &loads)] += 1;
+        }
+        assert_eq!(picked[0], 0);
+        assert!(picked[1] > 0 && picked[2] > 0, "{picked:?}");
+
+        let mut sorted = instances.clone();
+        sort_least_loaded(&load, &mut sorted);
+        assert_ne!(sorted[0].url, instances[0].url);
+        assert_eq!(sorted.len(), 3);
     }
 
     #[test]
-    fn test_invalid_capture_group() {
-        let url = "http://example.com/$2";
-        let captures = setup_captures("value1", r"(value1)");
-        let result = replace_args_in_url(url, captures);
-        assert!(result.is_err());
+    fn test_latency_weight() {
+        let fast = CrawledInstanceStatus::Ok(Duration::from_millis(100));
+        let slow = CrawledInstanceStatus::Ok(Duration::from_millis(400));
+        assert_eq!(latency_weight(&fast, 1.0) / latency_weight(&slow, 1.0), 4.0);
+        assert_eq!(
+            latency_weight(&fast, 2.0) / latency_weight(&slow, 2.0),
+            16.0
+        );
+        assert_eq!(latency_weight(&fast, 0.0), latency_weight(&slow, 0.0));
+        assert_eq!(
+            latency_weight(&CrawledInstanceStatus::Ok(Duration::ZERO), 1.0),
+            1.0
+        );
     }
 
     #[test]
-    fn test_escape() {
-        let url = r"http://example.com/\$1";
-        let captures = setup_captures("value", r"(value)");
-        let result = replace_args_in_url(url, captures);
-        assert_eq!(result.unwrap(), "http://example.com/$1");
+    fn test_sticky_score_stable() {
+        let urls = [
+            "https://a.example/",
+            "https://b.example/",
+            "https://c.example/",
+        ];
+        fn pick<'a>(urls: &[&'a str]) -> &'a str {
+            urls.iter()
+                .max_by_key(|url| sticky_score("seed", url))
+                .unwrap()
+        }
+        let picked = pick(&urls);
+        // Removing other instances does not move the user.
+        let others: Vec<&str> = urls.iter().copied().filter(|u| *u != picked).collect();
+        assert_eq!(pick(&[others[0], picked]), picked);
+        assert_eq!(pick(&[picked, others[1]]), picked);
+        assert_eq!(sticky_score("seed", picked), sticky_score("seed", picked));
+        assert_ne!(sticky_score("seed", picked), sticky_score("other", picked));
     }
 
     #[test]
-    fn test_multiple_escape_characters() {
-        let url = r"http://example.com/\\\$1";
-        let captures = setup_captures("value", r"(value)");
-        let result = replace_args_in_url(url, captures);
-        assert_eq!(result.unwrap(), r"http://example.com/\$1");
+    fn test_custom_and_blocked_instances() {
+        let instance = |url: &str, tags: &[&str]| CrawledInstance {
+            url: Url::parse(url).unwrap(),
+            status: CrawledInstanceStatus::Ok(Duration::from_millis(10)),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            history: Default::default(),
+            location: None,
+            weight: 1,
+        };
+        let crawled = CrawledService {
+            name: "a".to_string(),
+            instances: vec![
+                instance("https://a.example/", &["https"]),
+                instance("https://b.example/", &["https"]),
+            ],
+        };
+        let custom = [instance("http://self.example/", &[])];
+        let user_config = UserConfig {
+            required_tags: vec!["https".to_string()],
+            blocked_instances: vec!["a.example".to_string()],
+            ..Default::default()
+        };
+        let urls: Vec<&str> = get_redirect_instances(&crawled, &user_config, &custom)
+            .unwrap()
+            .iter()
+            .map(|i| i.url.as_str())
+            .collect();
+        // Custom instances are not filtered by tags.
+        assert_eq!(urls, vec!["https://b.example/", "http://self.example/"]);
     }
 }
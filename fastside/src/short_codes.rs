@@ -0,0 +1,484 @@
+//! User configs stored server-side under short random codes.
+//!
+//! Store is an append-only file of JSON lines, one per created or renewed
+//! code, so it survives restarts. Later lines override earlier ones. The file
+//! is compacted to live codes once most of its lines are stale, or once it
+//! has malformed lines, e.g. one left truncated by a crash. Configs are kept
+//! as config strings, the same format as in cookies.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use fastside_shared::{
+    config::{ShortCodesConfig, UserConfig},
+    errors::UserConfigError,
+};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const CODE_LENGTH: usize = 8;
+/// Store is never compacted below this number of lines.
+const MIN_COMPACT_LINES: usize = 1000;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum ShortCodeError {
+    #[error("short codes are disabled")]
+    Disabled,
+    #[error("short code not found or expired")]
+    NotFound,
+    #[error("config is too large: {0} bytes, maximum is {1}")]
+    TooLarge(usize, usize),
+    #[error("short code store is full")]
+    Full,
+    #[error("too many short codes created, try again later")]
+    RateLimited,
+    #[error("failed to save short codes: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("user config error: `{0}`")]
+    UserConfig(#[from] UserConfigError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredConfig {
+    code: String,
+    config: String,
+    expires: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+struct Codes {
+    /// Stored configs by code.
+    by_code: HashMap<String, StoredConfig>,
+    /// Codes by config string, to return the same code for the same config.
+    by_config: HashMap<String, String>,
+    /// Lines in store file.
+    lines: usize,
+    /// Store file has malformed lines and must be rewritten.
+    malformed: bool,
+    /// Start of rate limit window and codes created in it.
+    window: Option<(Instant, u32)>,
+}
+
+impl Codes {
+    fn insert(&mut self, stored: StoredConfig) {
+        if let Some(previous) = self.by_code.remove(&stored.code) {
+            self.by_config.remove(&previous.config);
+        }
+        self.by_config
+            .insert(stored.config.clone(), stored.code.clone());
+        self.by_code.insert(stored.code.clone(), stored);
+    }
+
+    fn remove_expired(&mut self, now: DateTime<Utc>) {
+        let by_config = &mut self.by_config;
+        self.by_code.retain(|_, stored| {
+            let alive = stored.expires > now;
+            if !alive {
+                by_config.remove(&stored.config);
+            }
+            alive
+        });
+    }
+
+    /// Count created code, returning `false` if rate limit is exceeded.
+    fn take_rate_limit(&mut self, limit: u32) -> bool {
+        let now = Instant::now();
+        let (start, count) = match self.window {
+            Some((start, count)) if start.elapsed() < RATE_LIMIT_WINDOW => (start, count),
+            _ => (now, 0),
+        };
+        if count >= limit {
+            return false;
+        }
+        self.window = Some((start, count + 1));
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct ShortCodeStore {
+    config: ShortCodesConfig,
+    codes: Mutex<Codes>,
+    /// Held for the whole of [`ShortCodeStore::create`], so codes are only
+    /// changed by one writer and the file always matches memory.
+    writer: tokio::sync::Mutex<()>,
+}
+
+impl ShortCodeStore {
+    /// Open store configured in `config`, loading existing codes from file.
+    pub fn open(config: &ShortCodesConfig) -> Result<Self> {
+        let mut codes = Codes::default();
+        if let Some(path) = &config.store
+            && path.is_file()
+        {
+            let content =
+                std::fs::read_to_string(path).context("failed to read short codes store")?;
+            for (number, line) in content.lines().enumerate() {
+                if line.is_empty() {
+                    continue;
+                }
+                codes.lines += 1;
+                match serde_json::from_str(line) {
+                    Ok(stored) => codes.insert(stored),
+                    Err(e) => {
+                        warn!(
+                            "Skipping malformed line {} of short codes store: {e}",
+                            number + 1
+                        );
+                        codes.malformed = true;
+                    }
+                }
+            }
+            // Appending after a truncated line would corrupt the new line too.
+            codes.malformed |= !content.is_empty() && !content.ends_with('\n');
+            codes.remove_expired(Utc::now());
+        }
+        Ok(Self {
+            config: config.clone(),
+            codes: Mutex::new(codes),
+            writer: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.store.is_some()
+    }
+
+    fn store_path(&self) -> Result<&PathBuf, ShortCodeError> {
+        self.config.store.as_ref().ok_or(ShortCodeError::Disabled)
+    }
+
+    /// Store config and return its code with expiration time.
+    ///
+    /// Storing config which is already stored returns the same code. Sticky
+    /// seed is not stored, so users of shared config get their own seeds.
+    pub async fn create(
+        &self,
+        user_config: &UserConfig,
+    ) -> Result<(String, DateTime<Utc>), ShortCodeError> {
+        let path = self.store_path()?;
+        let config = UserConfig {
+            sticky_seed: None,
+            ..user_config.clone()
+        }
+        .to_config_string()?;
+        if config.len() > self.config.max_config_size {
+            return Err(ShortCodeError::TooLarge(
+                config.len(),
+                self.config.max_config_size,
+            ));
+        }
+        let now = Utc::now();
+        // Too large TTL means codes never expire.
+        let expires = chrono::Duration::from_std(self.config.ttl)
+            .ok()
+            .and_then(|ttl| now.checked_add_signed(ttl))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+
+        let _writer = self.writer.lock().await;
+        let (stored, compacted) = {
+            let mut codes = self.codes.lock().unwrap();
+            let code = match codes.by_config.get(&config) {
+                Some(code) => code.clone(),
+                None => {
+                    codes.remove_expired(now);
+                    if codes.by_code.len() >= self.config.max_codes {
+                        return Err(ShortCodeError::Full);
+                    }
+                    if !codes.take_rate_limit(self.config.max_creates_per_minute) {
+                        return Err(ShortCodeError::RateLimited);
+                    }
+                    loop {
+                        let code = generate_code();
+                        if !codes.by_code.contains_key(&code) {
+                            break code;
+                        }
+                    }
+                }
+            };
+            let stored = StoredConfig {
+                code,
+                config,
+                expires,
+            };
+            // Most lines are renewals, expired codes or malformed, rewrite
+            // live ones.
+            let compacted = (codes.malformed
+                || codes.lines + 1 > MIN_COMPACT_LINES.max(codes.by_code.len() * 2))
+            .then(|| {
+                codes
+                    .by_code
+                    .values()
+                    .filter(|live| live.expires > now && live.code != stored.code)
+                    .cloned()
+                    .chain(std::iter::once(stored.clone()))
+                    .collect::<Vec<_>>()
+            });
+            (stored, compacted)
+        };
+
+        let path = path.clone();
+        let (stored, compacted) = tokio::task::spawn_blocking(move || {
+            match &compacted {
+                Some(codes) => rewrite(&path, codes),
+                None => append(&path, &stored),
+            }
+            .map(|_| (stored, compacted.is_some()))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        // Memory is only updated once the code is saved, so every code handed
+        // out survives a restart.
+        let code = stored.code.clone();
+        let mut codes = self.codes.lock().unwrap();
+        codes.insert(stored);
+        if compacted {
+            codes.remove_expired(now);
+            codes.lines = codes.by_code.len();
+            codes.malformed = false;
+        } else {
+            codes.lines += 1;
+        }
+        Ok((code, expires))
+    }
+
+    /// Get config stored under code.
+    pub fn get(&self, code: &str) -> Result<UserConfig, ShortCodeError> {
+        if !self.is_enabled() {
+            return Err(ShortCodeError::Disabled);
+        }
+        let config = match self.codes.lock().unwrap().by_code.get(code) {
+            Some(stored) if stored.expires > Utc::now() => stored.config.clone(),
+            _ => return Err(ShortCodeError::NotFound),
+        };
+        Ok(UserConfig {
+            // Configs stored by earlier versions may have it.
+            sticky_seed: None,
+            ..UserConfig::from_config_string(&config)?
+        })
+    }
+}
+
+fn generate_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CODE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+fn to_line(stored: &StoredConfig) -> std::io::Result<String> {
+    let mut line = serde_json::to_string(stored).map_err(std::io::Error::other)?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn append(path: &Path, stored: &StoredConfig) -> std::io::Result<()> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(to_line(stored)?.as_bytes())
+}
+
+/// Write codes to temporary file and move it over the store, so the store is
+/// never left half-written.
+fn rewrite(path: &Path, codes: &[StoredConfig]) -> std::io::Result<()> {
+    let mut content = String::new();
+    for stored in codes {
+        content.push_str(&to_line(stored)?);
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_short_codes() {
+        let path = std::env::temp_dir().join(format!("fastside-codes-{}.jsonl", generate_code()));
+        let config = ShortCodesConfig {
+            store: Some(path.clone()),
+            ..Default::default()
+        };
+        let user_config = UserConfig {
+            required_tags: vec!["tor".to_string()],
+            sticky_seed: Some("seed".to_string()),
+            ..Default::default()
+        };
+
+        let store = ShortCodeStore::open(&config).unwrap();
+        let (code, _) = store.create(&user_config).await.unwrap();
+        assert_eq!(code.len(), CODE_LENGTH);
+        assert_eq!(store.create(&user_config).await.unwrap().0, code);
+        assert!(matches!(
+            store.get("missing"),
+            Err(ShortCodeError::NotFound)
+        ));
+
+        // Codes survive reopening, renewals are appended.
+        let store = ShortCodeStore::open(&config).unwrap();
+        assert_eq!(store.codes.lock().unwrap().lines, 2);
+        let stored = store.get(&code).unwrap();
+        assert_eq!(stored.required_tags, vec!["tor"]);
+        assert_eq!(stored.sticky_seed, None);
+        std::fs::remove_file(&path).unwrap();
+
+        let store = ShortCodeStore::open(&ShortCodesConfig {
+            max_config_size: 10,
+            ..config.clone()
+        })
+        .unwrap();
+        assert!(matches!(
+            store.create(&user_config).await,
+            Err(ShortCodeError::TooLarge(_, 10))
+        ));
+
+        let store = ShortCodeStore::open(&ShortCodesConfig {
+            max_creates_per_minute: 1,
+            ..config
+        })
+        .unwrap();
+        store.create(&user_config).await.unwrap();
+        assert!(matches!(
+            store.create(&UserConfig::default()).await,
+            Err(ShortCodeError::RateLimited)
+        ));
+        // Existing configs are returned regardless of rate limit.
+        store.create(&user_config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let disabled = ShortCodeStore::open(&ShortCodesConfig::default()).unwrap();
+        assert!(matches!(
+            disabled.create(&user_config).await,
+            Err(ShortCodeError::Disabled)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_creates_across_compaction() {
+        let path = std::env::temp_dir().join(format!("fastside-codes-{}.jsonl", generate_code()));
+        let config = ShortCodesConfig {
+            store: Some(path.clone()),
+            max_creates_per_minute: u32::MAX,
+            ..Default::default()
+        };
+        let store = Arc::new(ShortCodeStore::open(&config).unwrap());
+
+        // Renewals of one config push the store past compaction threshold
+        // while new codes are created alongside them.
+        let renewed = UserConfig {
+            required_tags: vec!["renewed".to_string()],
+            ..Default::default()
+        };
+        let tasks = (0..MIN_COMPACT_LINES * 2).map(|i| {
+            let store = store.clone();
+            let user_config = match i % 4 {
+                0 => UserConfig {
+                    required_tags: vec![format!("tag{i}")],
+                    ..Default::default()
+                },
+                _ => renewed.clone(),
+            };
+            tokio::spawn(async move {
+                let (code, _) = store.create(&user_config).await.unwrap();
+                (code, user_config)
+            })
+        });
+        let created = futures::future::join_all(tasks).await;
+
+        let store = ShortCodeStore::open(&config).unwrap();
+        assert!(store.codes.lock().unwrap().lines < MIN_COMPACT_LINES * 2);
+        for result in created {
+            let (code, user_config) = result.unwrap();
+            assert_eq!(
+                store.get(&code).unwrap().required_tags,
+                user_config.required_tags
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncated_store() {
+        let path = std::env::temp_dir().join(format!("fastside-codes-{}.jsonl", generate_code()));
+        let config = ShortCodesConfig {
+            store: Some(path.clone()),
+            ..Default::default()
+        };
+        let user_config = UserConfig {
+            required_tags: vec!["tor".to_string()],
+            ..Default::default()
+        };
+        let store = ShortCodeStore::open(&config).unwrap();
+        let (code, _) = store.create(&user_config).await.unwrap();
+
+        // Crash in the middle of appending a line.
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str(r#"{"code":"trunc"#);
+        std::fs::write(&path, content).unwrap();
+
+        let store = ShortCodeStore::open(&config).unwrap();
+        assert_eq!(store.get(&code).unwrap().required_tags, vec!["tor"]);
+        let (created, _) = store.create(&UserConfig::default()).await.unwrap();
+
+        // Malformed line is dropped by rewrite, so new code is not lost.
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("trunc"));
+        let store = ShortCodeStore::open(&config).unwrap();
+        assert!(!store.codes.lock().unwrap().malformed);
+        assert_eq!(store.codes.lock().unwrap().lines, 2);
+        assert!(store.get(&created).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_huge_ttl() {
+        let path = std::env::temp_dir().join(format!("fastside-codes-{}.jsonl", generate_code()));
+        let config = ShortCodesConfig {
+            store: Some(path.clone()),
+            ttl: Duration::MAX,
+            ..Default::default()
+        };
+        let store = ShortCodeStore::open(&config).unwrap();
+        let (code, expires) = store.create(&UserConfig::default()).await.unwrap();
+        assert_eq!(expires, DateTime::<Utc>::MAX_UTC);
+
+        let store = ShortCodeStore::open(&config).unwrap();
+        assert!(store.get(&code).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_codes_index() {
+        let mut codes = Codes::default();
+        for i in 0..3 {
+            codes.insert(StoredConfig {
+                code: "a".to_string(),
+                config: format!("config{i}"),
+                expires: Utc::now(),
+            });
+        }
+        assert_eq!(codes.by_code.len(), 1);
+        assert_eq!(codes.by_config.len(), 1);
+        assert_eq!(codes.by_config["config2"], "a");
+        codes.remove_expired(Utc::now());
+        assert!(codes.by_code.is_empty() && codes.by_config.is_empty());
+    }
+}
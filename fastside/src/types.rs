@@ -1,20 +1,99 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
 use fastside_shared::{
     config::{ProxyData, UserConfig},
+    matcher::{
+        DomainIndex, GroupIndex, InstanceIndex, ReplacementMatcher, ReverseMatcher, UrlMatcher,
+    },
     serde_types::ServicesData,
 };
+use tokio::sync::RwLock;
 
-pub struct CompiledRegexSearch {
-    pub regex: regex::Regex,
-    pub url: String,
-}
-
-pub type Regexes = HashMap<String, Vec<CompiledRegexSearch>>;
-
+/// Immutable snapshot of everything derived from services file and config.
 #[derive(Debug)]
 pub struct LoadedData {
+    /// Monotonically increasing number of the snapshot, assigned by [`LoadedDataStore`].
+    pub generation: u64,
+    /// Time the snapshot became current, assigned by [`LoadedDataStore`].
+    pub loaded_at: DateTime<Utc>,
     pub services: ServicesData,
     pub proxies: ProxyData,
     pub default_user_config: UserConfig,
+    pub matcher: UrlMatcher,
+    pub instance_index: InstanceIndex,
+    pub domains: DomainIndex,
+    pub reverse_matcher: ReverseMatcher,
+    pub replacements: ReplacementMatcher,
+    pub groups: GroupIndex,
+}
+
+/// Holds current [`LoadedData`] snapshot.
+///
+/// Readers get a cheap `Arc` clone of the snapshot and never observe partially
+/// reloaded data. Reloads swap the whole snapshot at once.
+#[derive(Debug)]
+pub struct LoadedDataStore {
+    current: RwLock<Arc<LoadedData>>,
+}
+
+impl LoadedDataStore {
+    pub fn new(mut data: LoadedData) -> Self {
+        data.generation = 1;
+        data.loaded_at = Utc::now();
+        Self {
+            current: RwLock::new(Arc::new(data)),
+        }
+    }
+
+    /// Get current snapshot.
+    #[inline]
+    pub async fn snapshot(&self) -> Arc<LoadedData> {
+        self.current.read().await.clone()
+    }
+
+    /// Replace current snapshot and return generation of the new one.
+    pub async fn replace(&self, mut data: LoadedData) -> u64 {
+        let mut current = self.current.write().await;
+        data.generation = current.generation + 1;
+        data.loaded_at = Utc::now();
+        let generation = data.generation;
+        *current = Arc::new(data);
+        generation
+    }
+}
+
+#[cfg(test)]
+impl LoadedData {
+    pub fn empty() -> Self {
+        Self {
+            generation: 0,
+            loaded_at: DateTime::UNIX_EPOCH,
+            services: ServicesData::new(),
+            proxies: ProxyData::new(),
+            default_user_config: UserConfig::default(),
+            matcher: UrlMatcher::new(&[]).unwrap(),
+            instance_index: InstanceIndex::default(),
+            domains: DomainIndex::default(),
+            reverse_matcher: ReverseMatcher::default(),
+            replacements: ReplacementMatcher::default(),
+            groups: GroupIndex::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replace_increments_generation() {
+        let store = LoadedDataStore::new(LoadedData::empty());
+        let old = store.snapshot().await;
+        assert_eq!(old.generation, 1);
+        assert_eq!(store.replace(LoadedData::empty()).await, 2);
+        assert_eq!(store.snapshot().await.generation, 2);
+        // Old snapshot stays valid for readers that still hold it.
+        assert_eq!(old.generation, 1);
+    }
 }
@@ -1 +1,2 @@
+pub mod url;
 pub mod user_config;
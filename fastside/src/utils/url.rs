@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+
+use fastside_shared::config::QueryPrecedence;
+use url::Url;
+
+/// Split decoded path into path and fragment.
+///
+/// Browsers never send fragments, so fragments may only arrive url-encoded
+/// (`%23`) inside the path.
+pub fn split_fragment(path: &str) -> (&str, Option<&str>) {
+    match path.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (path, None),
+    }
+}
+
+/// Get query of URL, which may have no scheme, without fragment.
+pub fn url_query(url: &str) -> &str {
+    let url = url.split('#').next().unwrap_or_default();
+    url.split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+}
+
+/// Raw query pair with its decoded key and value.
+struct QueryPair<'a> {
+    raw: &'a str,
+    decoded: (String, String),
+}
+
+fn query_pairs(query: &str) -> Vec<QueryPair<'_>> {
+    query
+        .split('&')
+        .filter(|raw| !raw.is_empty())
+        .map(|raw| QueryPair {
+            raw,
+            decoded: url::form_urlencoded::parse(raw.as_bytes())
+                .into_owned()
+                .next()
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Merge query parameters of the request into the URL.
+///
+/// When a parameter is present in both URL and request, only the values from
+/// the side chosen by `precedence` are kept. Duplicate pairs are dropped.
+/// Kept pairs are copied as is, so their encoding and valueless parameters
+/// like `?foo` are preserved.
+pub fn merge_query(url: &mut Url, request_query: &str, precedence: QueryPrecedence) {
+    if request_query.is_empty() {
+        return;
+    }
+    let url_query = url.query().unwrap_or_default().to_string();
+    let url_pairs = query_pairs(&url_query);
+    let request_pairs = query_pairs(request_query);
+
+    let url_keys: HashSet<&str> = url_pairs.iter().map(|p| p.decoded.0.as_str()).collect();
+    let request_keys: HashSet<&str> = request_pairs.iter().map(|p| p.decoded.0.as_str()).collect();
+
+    let mut merged: Vec<&QueryPair> = Vec::new();
+    let url_iter = url_pairs.iter().filter(|p| {
+        precedence == QueryPrecedence::Template || !request_keys.contains(p.decoded.0.as_str())
+    });
+    let request_iter = request_pairs.iter().filter(|p| {
+        precedence == QueryPrecedence::Request || !url_keys.contains(p.decoded.0.as_str())
+    });
+    for pair in url_iter.chain(request_iter) {
+        if !merged.iter().any(|p| p.decoded == pair.decoded) {
+            merged.push(pair);
+        }
+    }
+
+    let merged: Vec<&str> = merged.iter().map(|p| p.raw).collect();
+    url.set_query((!merged.is_empty()).then(|| merged.join("&")).as_deref());
+}
+
+/// Build final redirect URL from redirect target and request parts.
+pub fn compose_redirect_url(
+    target: &str,
+    request_query: &str,
+    fragment: Option<&str>,
+    precedence: QueryPrecedence,
+) -> Result<Url, url::ParseError> {
+    let mut url = Url::parse(target)?;
+    merge_query(&mut url, request_query, precedence);
+    if let Some(fragment) = fragment {
+        url.set_fragment(Some(fragment));
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_query_request_precedence() {
+        let url = compose_redirect_url(
+            "https://example.org/watch?v=x&t=0",
+            "t=10&list=y",
+            None,
+            QueryPrecedence::Request,
+        )
+        .unwrap();
+        assert_eq!(url.as_str(), "https://example.org/watch?v=x&t=10&list=y");
+    }
+
+    #[test]
+    fn test_merge_query_template_precedence() {
+        let url = compose_redirect_url(
+            "https://example.org/watch?v=x&t=0",
+            "t=10&v=x&v=z",
+            None,
+            QueryPrecedence::Template,
+        )
+        .unwrap();
+        assert_eq!(url.as_str(), "https://example.org/watch?v=x&t=0");
+    }
+
+    #[test]
+    fn test_merge_query_dedup() {
+        let url = compose_redirect_url(
+            "https://example.org/search",
+            "q=a&q=a&q=b",
+            Some("top"),
+            QueryPrecedence::Request,
+        )
+        .unwrap();
+        assert_eq!(url.as_str(), "https://example.org/search?q=a&q=b#top");
+    }
+
+    #[test]
+    fn test_merge_query_keeps_raw_pairs() {
+        let url = compose_redirect_url(
+            "https://example.org/search?foo&q=a%20b",
+            "bar&page=2+3&q=c",
+            None,
+            QueryPrecedence::Template,
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://example.org/search?foo&q=a%20b&bar&page=2+3"
+        );
+    }
+
+    #[test]
+    fn test_empty_request_query_keeps_url() {
+        let url = compose_redirect_url(
+            "https://example.org/?url=a%2Fb",
+            "",
+            None,
+            QueryPrecedence::Request,
+        )
+        .unwrap();
+        assert_eq!(url.as_str(), "https://example.org/?url=a%2Fb");
+    }
+
+    #[test]
+    fn test_url_query() {
+        assert_eq!(url_query("youtu.be/abc?si=x&t=42#top"), "si=x&t=42");
+        assert_eq!(url_query("https://youtu.be/abc"), "");
+    }
+
+    #[test]
+    fn test_split_fragment() {
+        assert_eq!(split_fragment("a/b#c"), ("a/b", Some("c")));
+        assert_eq!(split_fragment("a/b"), ("a/b", None));
+    }
+}
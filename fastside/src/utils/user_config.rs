@@ -1,16 +1,98 @@
-use actix_web::HttpRequest;
-use fastside_shared::config::UserConfig;
+use actix_web::{HttpRequest, web};
+use fastside_shared::{config::UserConfig, errors::UserConfigError};
 
-pub fn load_settings_cookie(req: &HttpRequest, default: &UserConfig) -> UserConfig {
-    let cookie = match req.cookie("config") {
-        Some(cookie) => cookie,
+use crate::{errors::RedirectError, short_codes::ShortCodeStore};
+
+/// Query parameter carrying user config string.
+pub const CONFIG_QUERY_PARAM: &str = "fs_config";
+
+/// Load user config from cookie, `default` if there is no cookie.
+pub fn load_settings_cookie(
+    req: &HttpRequest,
+    default: &UserConfig,
+) -> Result<UserConfig, UserConfigError> {
+    match req.cookie("config") {
+        Some(cookie) => UserConfig::from_config_string(cookie.value()),
         None => {
             debug!("Cookie not found");
-            return default.clone();
+            Ok(default.clone())
         }
-    };
-    UserConfig::from_config_string(cookie.value()).unwrap_or_else(|_| {
-        debug!("invalid cookie");
+    }
+}
+
+/// Load user config of request.
+///
+/// Config string from `fs_config` query parameter wins over one embedded in
+/// path as `/~<config>/` or short code in path as `/c/<code>/`, which win
+/// over cookie. Invalid config strings and unknown codes in query or path are
+/// errors. Invalid cookie is ignored, so redirects keep working, and reported
+/// on the configure page.
+pub fn load_user_config(
+    req: &HttpRequest,
+    default: &UserConfig,
+) -> Result<UserConfig, RedirectError> {
+    let from_query = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(key, _)| key == CONFIG_QUERY_PARAM)
+        .map(|(_, value)| value.into_owned());
+    if let Some(config) = from_query.or_else(|| req.match_info().get("config").map(str::to_string))
+    {
+        return Ok(UserConfig::from_config_string(&config)?);
+    }
+    if let Some(code) = req.match_info().get("code") {
+        let short_codes = req
+            .app_data::<web::Data<ShortCodeStore>>()
+            .expect("short codes store is not registered");
+        return Ok(short_codes.get(code)?);
+    }
+    Ok(load_settings_cookie(req, default).unwrap_or_else(|e| {
+        debug!("Ignoring invalid config cookie: {e}");
         default.clone()
-    })
+    }))
+}
+
+/// Remove `fs_config` parameter from query string, keeping the rest as is.
+pub fn strip_config_param(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|pair| pair.split('=').next() != Some(CONFIG_QUERY_PARAM))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_strip_config_param() {
+        assert_eq!(strip_config_param("v=x&fs_config=abc&t=1"), "v=x&t=1");
+        assert_eq!(strip_config_param("fs_config=abc"), "");
+        assert_eq!(strip_config_param("v=a%26b"), "v=a%26b");
+    }
+
+    #[test]
+    fn test_load_user_config() {
+        let default = UserConfig::default();
+        let config = UserConfig {
+            required_tags: vec!["tor".to_string()],
+            ..Default::default()
+        };
+        let encoded = config.to_config_string().unwrap();
+
+        let req = TestRequest::default()
+            .uri(&format!("/invidious/?{CONFIG_QUERY_PARAM}={encoded}"))
+            .to_http_request();
+        let loaded = load_user_config(&req, &default).unwrap();
+        assert_eq!(loaded.required_tags, vec!["tor"]);
+
+        let req = TestRequest::default()
+            .uri(&format!("/invidious/?{CONFIG_QUERY_PARAM}=not-a-config"))
+            .to_http_request();
+        assert!(load_user_config(&req, &default).is_err());
+
+        let req = TestRequest::default().uri("/invidious/").to_http_request();
+        let loaded = load_user_config(&req, &default).unwrap();
+        assert_eq!(loaded.required_tags, default.required_tags);
+    }
 }